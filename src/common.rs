@@ -18,6 +18,7 @@ use cita_cloud_proto::blockchain::{
 };
 use cita_cloud_proto::common::Address;
 use status_code::StatusCode;
+use std::fmt::Write;
 use std::fs;
 use std::path::Path;
 use toml::macros::Deserialize;
@@ -39,6 +40,28 @@ pub fn h160_address_check(address: Option<&Address>) -> Result<(), StatusCode> {
     }
 }
 
+/// Lowercase hex of `bytes`, without `0x`.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+            let _ = write!(s, "{:02x}", b);
+            s
+        })
+}
+
+/// Decodes hex without `0x`, failing with `DecodeError` on anything but pairs
+/// of hex digits.
+pub fn decode_hex(s: &str) -> Result<Vec<u8>, StatusCode> {
+    if s.len() & 1 == 1 || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(StatusCode::DecodeError);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| StatusCode::DecodeError))
+        .collect()
+}
+
 pub fn get_tx_hash(raw_tx: &RawTransaction) -> Result<&[u8], StatusCode> {
     match raw_tx.tx {
         Some(Tx::NormalTx(ref normal_tx)) => Ok(&normal_tx.transaction_hash),
@@ -100,6 +123,15 @@ mod tests {
         port: u16,
     }
 
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(decode_hex("00ff7A"), Ok(vec![0, 0xff, 0x7a]));
+        assert_eq!(encode_hex(&[0, 0xff, 0x7a]), "00ff7a");
+        assert_eq!(decode_hex(""), Ok(vec![]));
+        assert_eq!(decode_hex("+f"), Err(StatusCode::DecodeError));
+        assert_eq!(decode_hex("abc"), Err(StatusCode::DecodeError));
+    }
+
     #[test]
     fn it_works() {
         let config: ExecutorConfig = read_toml("src/example/sample.toml", "executor");
@@ -0,0 +1,127 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hashes and recovers many items with any [`CryptoProvider`].
+//!
+//! Up to `concurrency` items are in flight at once and results keep the
+//! order of the items. The crypto service has no batch RPCs for these, so
+//! with [`GrpcCrypto`](super::GrpcCrypto) each item is still one call.
+
+use super::CryptoProvider;
+use futures::stream::{self, StreamExt};
+use log::warn;
+use status_code::StatusCode;
+use std::fmt;
+
+/// Items in flight at once by default.
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 64;
+
+/// The first item of a batch that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchError {
+    pub index: usize,
+    pub status: StatusCode,
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "item {} failed: {}", self.index, self.status)
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+impl From<BatchError> for StatusCode {
+    fn from(e: BatchError) -> Self {
+        e.status
+    }
+}
+
+pub async fn hash_batch<P, D>(
+    provider: &P,
+    data: &[D],
+    concurrency: usize,
+) -> Result<Vec<Vec<u8>>, BatchError>
+where
+    P: CryptoProvider + ?Sized,
+    D: AsRef<[u8]>,
+{
+    let hashes = stream::iter(data)
+        .map(|data| provider.hash_data(data.as_ref()))
+        .buffered(concurrency.max(1));
+    collect_batch("hash_batch", hashes, data.len()).await
+}
+
+/// Recovers the signer address of every `(signature, msg)`.
+pub async fn recover_batch<P, S, M>(
+    provider: &P,
+    items: &[(S, M)],
+    concurrency: usize,
+) -> Result<Vec<Vec<u8>>, BatchError>
+where
+    P: CryptoProvider + ?Sized,
+    S: AsRef<[u8]>,
+    M: AsRef<[u8]>,
+{
+    let addresses = stream::iter(items)
+        .map(|(signature, msg)| provider.recover_signature(signature.as_ref(), msg.as_ref()))
+        .buffered(concurrency.max(1));
+    collect_batch("recover_batch", addresses, items.len()).await
+}
+
+async fn collect_batch<R>(
+    name: &str,
+    mut results: R,
+    len: usize,
+) -> Result<Vec<Vec<u8>>, BatchError>
+where
+    R: stream::Stream<Item = Result<Vec<u8>, StatusCode>> + Unpin,
+{
+    let mut collected = Vec::with_capacity(len);
+    while let Some(result) = results.next().await {
+        let index = collected.len();
+        collected.push(result.map_err(|status| {
+            warn!("{}: item {} of {} failed: {}", name, index, len, status);
+            BatchError { index, status }
+        })?);
+    }
+    Ok(collected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::mock::MockCrypto;
+
+    #[tokio::test]
+    async fn keep_order_and_report_failed_item() {
+        let crypto = MockCrypto::new().with_delay();
+        let data = [vec![30u8], vec![0], vec![10, 1]];
+        let hashes = hash_batch(&crypto, &data, 2).await.unwrap();
+        assert_eq!(hashes, data);
+
+        let signature = crypto.sign_message(&[]).await.unwrap();
+        let items = [(signature, vec![]), (vec![], vec![]), (vec![], vec![])];
+        let err = recover_batch(&crypto, &items, DEFAULT_BATCH_CONCURRENCY)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            BatchError {
+                index: 1,
+                status: StatusCode::SigLenError
+            }
+        );
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keccak256 and secp256k1 in process, enabled by the `eth` feature.
+//!
+//! Matches `crypto_eth`: messages are 32 byte hashes, signatures are
+//! `r || s || v` with `v` the recovery id, and addresses are the last 20
+//! bytes of the hash of the uncompressed public key.
+
+use super::CryptoProvider;
+use crate::common::{ADDR_BYTES_LEN, HASH_BYTES_LEN};
+use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
+use log::warn;
+use sha3::{Digest, Keccak256};
+use status_code::StatusCode;
+
+pub const ETH_SIGNATURE_BYTES_LEN: usize = 65;
+
+/// Local Keccak256 and secp256k1.
+///
+/// Without a private key it still hashes and recovers signatures, but cannot sign.
+#[derive(Debug, Clone, Default)]
+pub struct EthCrypto {
+    signing_key: Option<SigningKey>,
+}
+
+impl EthCrypto {
+    pub fn new(private_key: &[u8]) -> Result<Self, StatusCode> {
+        let signing_key = SigningKey::from_slice(private_key).map_err(|e| {
+            warn!("EthCrypto: invalid private key: {}", e);
+            StatusCode::ConstructKeyPairError
+        })?;
+        Ok(EthCrypto {
+            signing_key: Some(signing_key),
+        })
+    }
+
+    /// Address of the private key, if any.
+    pub fn address(&self) -> Option<Vec<u8>> {
+        self.signing_key
+            .as_ref()
+            .map(|key| public_key_address(key.verifying_key()))
+    }
+}
+
+fn public_key_address(key: &VerifyingKey) -> Vec<u8> {
+    let pk = key.to_encoded_point(false);
+    // skip the 0x04 tag of uncompressed points
+    Keccak256::digest(&pk.as_bytes()[1..])[HASH_BYTES_LEN - ADDR_BYTES_LEN..].to_vec()
+}
+
+#[tonic::async_trait]
+impl CryptoProvider for EthCrypto {
+    async fn hash_data(&self, data: &[u8]) -> Result<Vec<u8>, StatusCode> {
+        Ok(Keccak256::digest(data).to_vec())
+    }
+
+    async fn sign_message(&self, msg: &[u8]) -> Result<Vec<u8>, StatusCode> {
+        let signing_key = self.signing_key.as_ref().ok_or_else(|| {
+            warn!("sign_message: EthCrypto has no private key");
+            StatusCode::SignError
+        })?;
+        if msg.len() != HASH_BYTES_LEN {
+            return Err(StatusCode::HashLenError);
+        }
+        let (signature, recovery_id) = signing_key.sign_prehash_recoverable(msg).map_err(|e| {
+            warn!("sign_message failed: {}", e);
+            StatusCode::SignError
+        })?;
+        let mut sig_bytes = signature.to_bytes().to_vec();
+        sig_bytes.push(recovery_id.to_byte());
+        Ok(sig_bytes)
+    }
+
+    async fn recover_signature(&self, signature: &[u8], msg: &[u8]) -> Result<Vec<u8>, StatusCode> {
+        if signature.len() != ETH_SIGNATURE_BYTES_LEN {
+            return Err(StatusCode::SigLenError);
+        }
+        if msg.len() != HASH_BYTES_LEN {
+            return Err(StatusCode::HashLenError);
+        }
+        let sig = Signature::from_slice(&signature[..64]).map_err(|e| {
+            warn!("recover_signature: invalid signature: {}", e);
+            StatusCode::ConstructSigError
+        })?;
+        let recovery_id = RecoveryId::from_byte(signature[64]).ok_or_else(|| {
+            warn!("recover_signature: invalid recovery id: {}", signature[64]);
+            StatusCode::ConstructSigError
+        })?;
+        let key = VerifyingKey::recover_from_prehash(msg, &sig, recovery_id).map_err(|e| {
+            warn!("recover_signature failed: {}", e);
+            StatusCode::SigCheckError
+        })?;
+        Ok(public_key_address(&key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{H160, H256};
+    use cita_cloud_proto::blockchain::BlockHeader;
+
+    #[tokio::test]
+    async fn sign_and_recover() {
+        let mut private_key = [0u8; 32];
+        private_key[31] = 1;
+        let crypto = EthCrypto::new(&private_key).unwrap();
+        let address = crypto.address().unwrap();
+        let expected: H160 = "7e5f4552091a69125d5dfcb7b8c2659029395bdf".parse().unwrap();
+        assert_eq!(address, expected.to_vec());
+
+        let msg = crypto.hash_data(b"").await.unwrap();
+        assert_eq!(
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+                .parse::<H256>()
+                .unwrap()
+                .to_vec(),
+            msg
+        );
+        let signature = crypto.sign_message(&msg).await.unwrap();
+        let verifier = EthCrypto::default();
+        assert_eq!(
+            verifier.recover_signature(&signature, &msg).await.unwrap(),
+            address
+        );
+        assert_eq!(
+            verifier.sign_message(&msg).await,
+            Err(StatusCode::SignError)
+        );
+
+        let header = BlockHeader {
+            height: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            verifier.get_block_hash(Some(&header)).await.unwrap().len(),
+            HASH_BYTES_LEN
+        );
+    }
+}
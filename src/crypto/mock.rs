@@ -0,0 +1,86 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A [`CryptoProvider`] for tests.
+//!
+//! Signatures are the address of the key followed by the message, so they
+//! recover to that address only for the same message.
+
+use super::CryptoProvider;
+use crate::common::ADDR_BYTES_LEN;
+use status_code::StatusCode;
+use std::time::Duration;
+
+pub(crate) struct MockCrypto {
+    key: [u8; ADDR_BYTES_LEN],
+    hash: fn(&[u8]) -> Vec<u8>,
+    delay: bool,
+}
+
+impl MockCrypto {
+    /// Hashes to the data itself and signs with the key `[1; 20]`.
+    pub(crate) fn new() -> Self {
+        MockCrypto {
+            key: [1; ADDR_BYTES_LEN],
+            hash: <[u8]>::to_vec,
+            delay: false,
+        }
+    }
+
+    /// Signs as `key` instead.
+    pub(crate) fn with_key(mut self, key: [u8; ADDR_BYTES_LEN]) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub(crate) fn with_hash(mut self, hash: fn(&[u8]) -> Vec<u8>) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// Waits the first byte of the data in ms before hashing it.
+    pub(crate) fn with_delay(mut self) -> Self {
+        self.delay = true;
+        self
+    }
+
+    pub(crate) fn address(&self) -> Vec<u8> {
+        self.key.to_vec()
+    }
+}
+
+#[tonic::async_trait]
+impl CryptoProvider for MockCrypto {
+    async fn hash_data(&self, data: &[u8]) -> Result<Vec<u8>, StatusCode> {
+        if self.delay {
+            let ms = data.first().copied().unwrap_or_default();
+            tokio::time::sleep(Duration::from_millis(ms.into())).await;
+        }
+        Ok((self.hash)(data))
+    }
+
+    async fn sign_message(&self, msg: &[u8]) -> Result<Vec<u8>, StatusCode> {
+        Ok([&self.key, msg].concat())
+    }
+
+    async fn recover_signature(&self, signature: &[u8], msg: &[u8]) -> Result<Vec<u8>, StatusCode> {
+        if signature.len() < ADDR_BYTES_LEN {
+            Err(StatusCode::SigLenError)
+        } else if &signature[ADDR_BYTES_LEN..] == msg {
+            Ok(signature[..ADDR_BYTES_LEN].to_vec())
+        } else {
+            Err(StatusCode::SigCheckError)
+        }
+    }
+}
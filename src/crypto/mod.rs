@@ -0,0 +1,192 @@
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Crypto operations of the chain.
+//!
+//! The free functions call the crypto service. [`CryptoProvider`] abstracts
+//! over them, with [`GrpcCrypto`] calling the service and, behind the `eth`
+//! and `sm` features, `EthCrypto` and `SmCrypto` doing the same work in
+//! process, e.g. to hash headers without a round trip, or in tests.
+//! [`batch`] works on many items at once with any of them, and
+//! [`verify_raw_tx`] checks the hashes and signatures of transactions that
+//! [`TxBuilder`] signs.
+
+pub mod batch;
+#[cfg(feature = "eth")]
+mod eth;
+#[cfg(test)]
+pub(crate) mod mock;
+mod provider;
+#[cfg(feature = "sm")]
+mod sm;
+mod tx;
+
+pub use batch::{BatchError, DEFAULT_BATCH_CONCURRENCY};
+#[cfg(feature = "eth")]
+pub use eth::{EthCrypto, ETH_SIGNATURE_BYTES_LEN};
+pub use provider::{CryptoProvider, GrpcCrypto, GrpcCryptoClient};
+#[cfg(feature = "sm")]
+pub use sm::{SmCrypto, SM2_PUBKEY_BYTES_LEN, SM2_SIGNATURE_BYTES_LEN};
+pub use tx::{hash_message, verify_raw_tx, TxBuilder};
+
+use crate::common::{ADDR_BYTES_LEN, HASH_BYTES_LEN};
+use crate::metrics::observe_client_call;
+use cita_cloud_proto::blockchain::BlockHeader;
+use cita_cloud_proto::client::CryptoClientTrait;
+use cita_cloud_proto::common::Address;
+use cita_cloud_proto::crypto::{HashDataRequest, RecoverSignatureRequest, SignMessageRequest};
+use log::warn;
+use prost::Message;
+use status_code::StatusCode;
+
+pub async fn hash_data<C: CryptoClientTrait>(
+    client: C,
+    data: &[u8],
+) -> Result<Vec<u8>, StatusCode> {
+    observe_client_call("crypto", "hash_data", async move {
+        let data = data.to_vec();
+        match client.hash_data(HashDataRequest { data }).await {
+            Ok(hash_respond) => {
+                let status_code =
+                    StatusCode::from(hash_respond.status.ok_or(StatusCode::NoneStatusCode)?.code);
+
+                if status_code != StatusCode::Success {
+                    Err(status_code)
+                } else {
+                    Ok(hash_respond.hash.ok_or(StatusCode::NoneHashResult)?.hash)
+                }
+            }
+            Err(status) => {
+                warn!("hash_data error: {}", status.to_string());
+                Err(StatusCode::CryptoServerNotReady)
+            }
+        }
+    })
+    .await
+}
+
+pub async fn get_block_hash<C: CryptoClientTrait>(
+    client: C,
+    header: Option<&BlockHeader>,
+) -> Result<Vec<u8>, StatusCode> {
+    match header {
+        Some(header) => {
+            let mut block_header_bytes = Vec::with_capacity(header.encoded_len());
+            header.encode(&mut block_header_bytes).map_err(|_| {
+                warn!("get_block_hash: encode block header failed");
+                StatusCode::EncodeError
+            })?;
+            let block_hash = hash_data(client, &block_header_bytes).await?;
+            Ok(block_hash)
+        }
+        None => Err(StatusCode::NoneBlockHeader),
+    }
+}
+
+pub async fn pk2address<C: CryptoClientTrait>(client: C, pk: &[u8]) -> Result<Vec<u8>, StatusCode> {
+    Ok(hash_data(client, pk).await?[HASH_BYTES_LEN - ADDR_BYTES_LEN..].to_vec())
+}
+
+pub async fn sign_message<C: CryptoClientTrait>(
+    client: C,
+    msg: &[u8],
+) -> Result<Vec<u8>, StatusCode> {
+    observe_client_call("crypto", "sign_message", async move {
+        let smr = client
+            .sign_message(SignMessageRequest { msg: msg.to_vec() })
+            .await
+            .map_err(|e| {
+                warn!("sign_message failed: {}", e.to_string());
+                StatusCode::CryptoServerNotReady
+            })?;
+
+        let status = StatusCode::from(smr.status.ok_or(StatusCode::NoneStatusCode)?);
+        if status != StatusCode::Success {
+            Err(status)
+        } else {
+            Ok(smr.signature)
+        }
+    })
+    .await
+}
+
+pub async fn recover_signature<C: CryptoClientTrait>(
+    client: C,
+    signature: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>, StatusCode> {
+    observe_client_call("crypto", "recover_signature", async move {
+        let rsr = client
+            .recover_signature(RecoverSignatureRequest {
+                msg: msg.to_vec(),
+                signature: signature.to_vec(),
+            })
+            .await
+            .map_err(|e| {
+                warn!("recover_signature failed: {}", e.to_string());
+                StatusCode::CryptoServerNotReady
+            })?;
+
+        let status = StatusCode::from(rsr.status.ok_or(StatusCode::NoneStatusCode)?);
+        if status != StatusCode::Success {
+            Err(status)
+        } else {
+            Ok(rsr.address)
+        }
+    })
+    .await
+}
+
+/// Checks that `signature` of `msg` was made by `expected`, failing with
+/// `InvalidSender` if another key made it.
+pub async fn verify_signature<C: GrpcCryptoClient>(
+    client: C,
+    signature: &[u8],
+    msg: &[u8],
+    expected: &Address,
+) -> Result<(), StatusCode> {
+    GrpcCrypto::new(client)
+        .verify_signature(signature, msg, expected)
+        .await
+}
+
+/// Checks that `proof`, a signature of the block hash, was made by the
+/// proposer of `header`, failing with `ProposalProofError` otherwise.
+pub async fn verify_block_proposer<C: GrpcCryptoClient>(
+    client: C,
+    header: &BlockHeader,
+    proof: &[u8],
+) -> Result<(), StatusCode> {
+    GrpcCrypto::new(client)
+        .verify_block_proposer(header, proof)
+        .await
+}
+
+/// Hashes every item of `data` with up to `concurrency` calls in flight.
+pub async fn hash_batch<C: GrpcCryptoClient, D: AsRef<[u8]>>(
+    client: C,
+    data: &[D],
+    concurrency: usize,
+) -> Result<Vec<Vec<u8>>, BatchError> {
+    batch::hash_batch(&GrpcCrypto::new(client), data, concurrency).await
+}
+
+/// Recovers the signer address of every `(signature, msg)` with up to
+/// `concurrency` calls in flight.
+pub async fn recover_batch<C: GrpcCryptoClient, S: AsRef<[u8]>, M: AsRef<[u8]>>(
+    client: C,
+    items: &[(S, M)],
+    concurrency: usize,
+) -> Result<Vec<Vec<u8>>, BatchError> {
+    batch::recover_batch(&GrpcCrypto::new(client), items, concurrency).await
+}
@@ -0,0 +1,193 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::common::{encode_hex, h160_address_check, ADDR_BYTES_LEN, HASH_BYTES_LEN};
+use cita_cloud_proto::blockchain::BlockHeader;
+use cita_cloud_proto::client::{CryptoClientTrait, InterceptedSvc};
+use cita_cloud_proto::common::Address;
+use cita_cloud_proto::crypto::crypto_service_client::CryptoServiceClient;
+use cita_cloud_proto::retry::RetryClient;
+use log::warn;
+use prost::Message;
+use status_code::StatusCode;
+
+/// The crypto operations of a chain, done by the crypto service or locally.
+///
+/// Signatures and addresses follow the crypto service of the same algorithm,
+/// so a local provider can check what the service signed and the reverse.
+#[tonic::async_trait]
+pub trait CryptoProvider: Send + Sync {
+    async fn hash_data(&self, data: &[u8]) -> Result<Vec<u8>, StatusCode>;
+
+    async fn sign_message(&self, msg: &[u8]) -> Result<Vec<u8>, StatusCode>;
+
+    /// Returns the address of the signer.
+    async fn recover_signature(&self, signature: &[u8], msg: &[u8]) -> Result<Vec<u8>, StatusCode>;
+
+    async fn pk2address(&self, pk: &[u8]) -> Result<Vec<u8>, StatusCode> {
+        Ok(self.hash_data(pk).await?[HASH_BYTES_LEN - ADDR_BYTES_LEN..].to_vec())
+    }
+
+    async fn get_block_hash(&self, header: Option<&BlockHeader>) -> Result<Vec<u8>, StatusCode> {
+        match header {
+            Some(header) => {
+                let mut block_header_bytes = Vec::with_capacity(header.encoded_len());
+                header.encode(&mut block_header_bytes).map_err(|_| {
+                    warn!("get_block_hash: encode block header failed");
+                    StatusCode::EncodeError
+                })?;
+                self.hash_data(&block_header_bytes).await
+            }
+            None => Err(StatusCode::NoneBlockHeader),
+        }
+    }
+
+    /// Checks that `signature` of `msg` was made by `expected`.
+    async fn verify_signature(
+        &self,
+        signature: &[u8],
+        msg: &[u8],
+        expected: &Address,
+    ) -> Result<(), StatusCode> {
+        h160_address_check(Some(expected))?;
+        let address = self.recover_signature(signature, msg).await?;
+        if address == expected.address {
+            Ok(())
+        } else {
+            warn!(
+                "verify_signature: signer 0x{} is not 0x{}",
+                encode_hex(&address),
+                encode_hex(&expected.address)
+            );
+            Err(StatusCode::InvalidSender)
+        }
+    }
+
+    /// Checks that `proof`, a signature of the block hash, was made by the
+    /// proposer of `header`.
+    async fn verify_block_proposer(
+        &self,
+        header: &BlockHeader,
+        proof: &[u8],
+    ) -> Result<(), StatusCode> {
+        if proof.is_empty() {
+            return Err(StatusCode::NoneProof);
+        }
+        let proposer = Address {
+            address: header.proposer.clone(),
+        };
+        h160_address_check(Some(&proposer))?;
+        let block_hash = self.get_block_hash(Some(header)).await?;
+        self.verify_signature(proof, &block_hash, &proposer)
+            .await
+            .map_err(|e| {
+                warn!(
+                    "verify_block_proposer: proof of block {} failed: {}",
+                    header.height, e
+                );
+                match e {
+                    StatusCode::InvalidSender => StatusCode::ProposalProofError,
+                    e => e,
+                }
+            })
+    }
+}
+
+/// A crypto service client, e.g. the `RetryClient` of `cita_cloud_proto`, or
+/// one that adds the trace context to its requests.
+pub trait GrpcCryptoClient: CryptoClientTrait + Clone + Send + Sync {}
+
+impl<C: CryptoClientTrait + Clone + Send + Sync> GrpcCryptoClient for C {}
+
+/// Calls the crypto service for every operation.
+#[derive(Debug, Clone)]
+pub struct GrpcCrypto<C = RetryClient<CryptoServiceClient<InterceptedSvc>>> {
+    client: C,
+}
+
+impl<C: GrpcCryptoClient> GrpcCrypto<C> {
+    pub fn new(client: C) -> Self {
+        GrpcCrypto { client }
+    }
+}
+
+#[tonic::async_trait]
+impl<C: GrpcCryptoClient> CryptoProvider for GrpcCrypto<C> {
+    async fn hash_data(&self, data: &[u8]) -> Result<Vec<u8>, StatusCode> {
+        super::hash_data(self.client.clone(), data).await
+    }
+
+    async fn sign_message(&self, msg: &[u8]) -> Result<Vec<u8>, StatusCode> {
+        super::sign_message(self.client.clone(), msg).await
+    }
+
+    async fn recover_signature(&self, signature: &[u8], msg: &[u8]) -> Result<Vec<u8>, StatusCode> {
+        super::recover_signature(self.client.clone(), signature, msg).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::mock::MockCrypto;
+
+    #[tokio::test]
+    async fn verify_signer() {
+        let crypto = MockCrypto::new();
+        let signer = Address {
+            address: crypto.address(),
+        };
+        let signature = crypto.sign_message(b"msg").await.unwrap();
+        assert_eq!(
+            crypto.verify_signature(&signature, b"msg", &signer).await,
+            Ok(())
+        );
+        let other = Address {
+            address: vec![2; ADDR_BYTES_LEN],
+        };
+        assert_eq!(
+            crypto.verify_signature(&signature, b"msg", &other).await,
+            Err(StatusCode::InvalidSender)
+        );
+        let short = Address {
+            address: vec![1; ADDR_BYTES_LEN - 1],
+        };
+        assert_eq!(
+            crypto.verify_signature(&signature, b"msg", &short).await,
+            Err(StatusCode::ProvideAddressError)
+        );
+
+        let header = BlockHeader {
+            height: 1,
+            proposer: signer.address,
+            ..Default::default()
+        };
+        let block_hash = crypto.get_block_hash(Some(&header)).await.unwrap();
+        let proof = crypto.sign_message(&block_hash).await.unwrap();
+        assert_eq!(crypto.verify_block_proposer(&header, &proof).await, Ok(()));
+        let other_proof = MockCrypto::new()
+            .with_key([2; ADDR_BYTES_LEN])
+            .sign_message(&block_hash)
+            .await
+            .unwrap();
+        assert_eq!(
+            crypto.verify_block_proposer(&header, &other_proof).await,
+            Err(StatusCode::ProposalProofError)
+        );
+        assert_eq!(
+            crypto.verify_block_proposer(&header, &[]).await,
+            Err(StatusCode::NoneProof)
+        );
+    }
+}
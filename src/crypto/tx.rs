@@ -0,0 +1,255 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::CryptoProvider;
+use crate::types::{H160, H256};
+use cita_cloud_proto::blockchain::raw_transaction::Tx;
+use cita_cloud_proto::blockchain::{RawTransaction, Transaction, UnverifiedTransaction, Witness};
+use cita_cloud_proto::common::Address;
+use log::warn;
+use prost::Message;
+use status_code::StatusCode;
+
+/// Hash of `msg` as the controller computes it, from its protobuf encoding.
+pub async fn hash_message<P, M>(provider: &P, msg: &M) -> Result<Vec<u8>, StatusCode>
+where
+    P: CryptoProvider + ?Sized,
+    M: Message,
+{
+    let mut buf = Vec::with_capacity(msg.encoded_len());
+    msg.encode(&mut buf).map_err(|_| {
+        warn!("hash_message: encode failed");
+        StatusCode::EncodeError
+    })?;
+    provider.hash_data(&buf).await
+}
+
+/// Builds a [`Transaction`] and signs it into a [`RawTransaction`].
+///
+/// ```ignore
+/// let raw_tx = TxBuilder::new(chain_id, nonce, 300_000, height + 100)
+///     .with_to(contract)
+///     .with_data(input)
+///     .sign(&provider)
+///     .await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct TxBuilder {
+    tx: Transaction,
+}
+
+impl TxBuilder {
+    pub fn new(
+        chain_id: H256,
+        nonce: impl Into<String>,
+        quota: u64,
+        valid_until_block: u64,
+    ) -> Self {
+        TxBuilder {
+            tx: Transaction {
+                chain_id: chain_id.to_vec(),
+                nonce: nonce.into(),
+                quota,
+                valid_until_block,
+                value: vec![0; H256::LEN],
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.tx.version = version;
+        self
+    }
+
+    /// Without a receiver the transaction creates a contract.
+    pub fn with_to(mut self, to: H160) -> Self {
+        self.tx.to = to.to_vec();
+        self
+    }
+
+    pub fn with_data(mut self, data: Vec<u8>) -> Self {
+        self.tx.data = data;
+        self
+    }
+
+    /// Big endian amount.
+    pub fn with_value(mut self, value: H256) -> Self {
+        self.tx.value = value.to_vec();
+        self
+    }
+
+    pub fn build(self) -> Transaction {
+        self.tx
+    }
+
+    /// Hashes and signs the transaction with `provider`, taking the sender
+    /// from the signature, so a signature that does not recover fails here.
+    pub async fn sign<P>(self, provider: &P) -> Result<RawTransaction, StatusCode>
+    where
+        P: CryptoProvider + ?Sized,
+    {
+        let transaction_hash = hash_message(provider, &self.tx).await?;
+        let signature = provider.sign_message(&transaction_hash).await?;
+        let sender = provider
+            .recover_signature(&signature, &transaction_hash)
+            .await?;
+        Ok(RawTransaction {
+            tx: Some(Tx::NormalTx(UnverifiedTransaction {
+                transaction: Some(self.tx),
+                transaction_hash,
+                witness: Some(Witness { signature, sender }),
+            })),
+        })
+    }
+}
+
+/// Checks that the hash of `raw_tx` is the hash of its transaction, and that
+/// every witness signed that hash.
+///
+/// Fails with `HashCheckError` on a wrong hash, and with `InvalidSender` on a
+/// signature made by another key than the witness sender.
+pub async fn verify_raw_tx<P>(provider: &P, raw_tx: &RawTransaction) -> Result<(), StatusCode>
+where
+    P: CryptoProvider + ?Sized,
+{
+    match raw_tx.tx {
+        Some(Tx::NormalTx(ref normal_tx)) => {
+            let tx = normal_tx
+                .transaction
+                .as_ref()
+                .ok_or(StatusCode::NoTransaction)?;
+            check_hash(provider, tx, &normal_tx.transaction_hash).await?;
+            let witness = normal_tx.witness.as_ref().ok_or(StatusCode::NoneWitness)?;
+            check_witness(provider, witness, &normal_tx.transaction_hash).await
+        }
+        Some(Tx::UtxoTx(ref utxo_tx)) => {
+            let tx = utxo_tx
+                .transaction
+                .as_ref()
+                .ok_or(StatusCode::NoTransaction)?;
+            check_hash(provider, tx, &utxo_tx.transaction_hash).await?;
+            if utxo_tx.witnesses.is_empty() {
+                return Err(StatusCode::NoneWitness);
+            }
+            for witness in &utxo_tx.witnesses {
+                check_witness(provider, witness, &utxo_tx.transaction_hash).await?;
+            }
+            Ok(())
+        }
+        None => Err(StatusCode::NoTransaction),
+    }
+}
+
+async fn check_hash<P, M>(provider: &P, tx: &M, tx_hash: &[u8]) -> Result<(), StatusCode>
+where
+    P: CryptoProvider + ?Sized,
+    M: Message,
+{
+    if hash_message(provider, tx).await? == tx_hash {
+        Ok(())
+    } else {
+        warn!("verify_raw_tx: transaction hash mismatch");
+        Err(StatusCode::HashCheckError)
+    }
+}
+
+async fn check_witness<P>(provider: &P, witness: &Witness, tx_hash: &[u8]) -> Result<(), StatusCode>
+where
+    P: CryptoProvider + ?Sized,
+{
+    let sender = Address {
+        address: witness.sender.clone(),
+    };
+    provider
+        .verify_signature(&witness.signature, tx_hash, &sender)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ADDR_BYTES_LEN;
+    use crate::crypto::mock::MockCrypto;
+
+    fn toy_crypto() -> MockCrypto {
+        MockCrypto::new().with_hash(|data| data.iter().rev().copied().collect())
+    }
+
+    #[tokio::test]
+    async fn verify_normal_tx() {
+        let crypto = toy_crypto();
+        let tx = Transaction {
+            nonce: "1".to_string(),
+            quota: 300_000,
+            valid_until_block: 100,
+            ..Default::default()
+        };
+        let tx_hash = hash_message(&crypto, &tx).await.unwrap();
+        let mut unverified = UnverifiedTransaction {
+            transaction: Some(tx),
+            transaction_hash: tx_hash.clone(),
+            witness: Some(Witness {
+                signature: crypto.sign_message(&tx_hash).await.unwrap(),
+                sender: vec![1; ADDR_BYTES_LEN],
+            }),
+        };
+        let raw_tx = |unverified: &UnverifiedTransaction| RawTransaction {
+            tx: Some(Tx::NormalTx(unverified.clone())),
+        };
+        assert_eq!(verify_raw_tx(&crypto, &raw_tx(&unverified)).await, Ok(()));
+
+        unverified.witness.as_mut().unwrap().sender = vec![2; ADDR_BYTES_LEN];
+        assert_eq!(
+            verify_raw_tx(&crypto, &raw_tx(&unverified)).await,
+            Err(StatusCode::InvalidSender)
+        );
+
+        unverified.transaction.as_mut().unwrap().nonce = "2".to_string();
+        assert_eq!(
+            verify_raw_tx(&crypto, &raw_tx(&unverified)).await,
+            Err(StatusCode::HashCheckError)
+        );
+
+        assert_eq!(
+            verify_raw_tx(&crypto, &RawTransaction { tx: None }).await,
+            Err(StatusCode::NoTransaction)
+        );
+    }
+
+    #[tokio::test]
+    async fn build_and_sign() {
+        let builder = TxBuilder::new(H256([7; H256::LEN]), "1", 300_000, 100)
+            .with_to(H160([2; H160::LEN]))
+            .with_data(vec![1, 2, 3]);
+        let crypto = toy_crypto();
+        let tx = builder.clone().build();
+        assert_eq!(tx.chain_id, vec![7; H256::LEN]);
+        assert_eq!(tx.value, vec![0; H256::LEN]);
+
+        let raw_tx = builder.sign(&crypto).await.unwrap();
+        assert_eq!(verify_raw_tx(&crypto, &raw_tx).await, Ok(()));
+        match raw_tx.tx {
+            Some(Tx::NormalTx(normal_tx)) => {
+                assert_eq!(normal_tx.transaction, Some(tx.clone()));
+                assert_eq!(
+                    normal_tx.transaction_hash,
+                    hash_message(&crypto, &tx).await.unwrap()
+                );
+                assert_eq!(normal_tx.witness.unwrap().sender, vec![1; ADDR_BYTES_LEN]);
+            }
+            _ => panic!("not a normal tx"),
+        }
+    }
+}
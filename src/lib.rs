@@ -14,10 +14,13 @@
 
 pub mod common;
 pub mod crypto;
+pub mod logging;
+pub mod merkle;
 pub mod metrics;
 pub mod network;
 pub mod signal;
 pub mod storage;
+pub mod types;
 pub mod wal;
 
 pub fn unix_now() -> u64 {
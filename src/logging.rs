@@ -0,0 +1,202 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Log levels that can be changed while the service runs, e.g. from the
+//! `/loglevel` endpoint of the metrics exporter.
+//!
+//! The default level only needs `log::set_max_level`, so it works with any
+//! logger. Per module levels need the service logger to be installed with
+//! [`init`], which wraps it to filter records by their target, and cannot be
+//! set before:
+//!
+//! ```ignore
+//! let logger = log4rs::Logger::new(config);
+//! cloud_util::logging::init(logger, LevelFilter::Info)?;
+//! ```
+//!
+//! The wrapped logger still applies its own filters, so it should let through
+//! every level that may be turned on at runtime.
+
+use lazy_static::lazy_static;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref FILTERS: RwLock<LogFilters> = RwLock::new(LogFilters {
+        level: log::max_level(),
+        modules: BTreeMap::new(),
+    });
+}
+
+/// Whether [`init`] installed the filtered logger.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+/// `FILTERS.level` while no module has a level of its own, otherwise
+/// `usize::MAX`, so that most records are filtered without taking the lock.
+static DEFAULT_ONLY: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Levels of the log records that are kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilters {
+    /// Level of the modules without a level of their own.
+    pub level: LevelFilter,
+    /// Levels of modules and their submodules, e.g. `consensus_raft::peer`.
+    pub modules: BTreeMap<String, LevelFilter>,
+}
+
+impl LogFilters {
+    /// Level of `target`, from its longest matching module.
+    pub fn level_of(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module.as_str()
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.level, |(_, level)| *level)
+    }
+
+    /// The most verbose level of any module.
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .values()
+            .copied()
+            .fold(self.level, |max, level| max.max(level))
+    }
+}
+
+/// Filters the records of `inner` by the current [`LogFilters`].
+struct FilteredLogger<L> {
+    inner: L,
+}
+
+impl<L: Log> Log for FilteredLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = match DEFAULT_ONLY.load(Ordering::Relaxed) {
+            usize::MAX => FILTERS.read().unwrap().level_of(metadata.target()),
+            level => level_filter(level),
+        };
+        metadata.level() <= level && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+fn level_filter(level: usize) -> LevelFilter {
+    LevelFilter::iter()
+        .find(|filter| *filter as usize == level)
+        .unwrap_or(LevelFilter::Trace)
+}
+
+/// Publishes `filters` to the logger and the `log` macros.
+fn apply(filters: &LogFilters) {
+    let default_only = if filters.modules.is_empty() {
+        filters.level as usize
+    } else {
+        usize::MAX
+    };
+    DEFAULT_ONLY.store(default_only, Ordering::Relaxed);
+    log::set_max_level(filters.max_level());
+}
+
+/// Module levels were set before [`init`] installed the filtered logger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotInitialized;
+
+impl fmt::Display for NotInitialized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "module log levels need the logger installed by logging::init"
+        )
+    }
+}
+
+impl std::error::Error for NotInitialized {}
+
+/// Installs `logger` as the global logger, filtered by `level` until changed.
+pub fn init(logger: impl Log + 'static, level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(FilteredLogger { inner: logger }))?;
+    INITIALIZED.store(true, Ordering::Relaxed);
+    set_level(level);
+    Ok(())
+}
+
+pub fn log_filters() -> LogFilters {
+    FILTERS.read().unwrap().clone()
+}
+
+/// Sets the level of the modules without a level of their own.
+pub fn set_level(level: LevelFilter) {
+    let mut filters = FILTERS.write().unwrap();
+    filters.level = level;
+    apply(&filters);
+}
+
+/// Sets the level of `module` and its submodules, or removes it with `None`.
+///
+/// Fails unless the logger was installed with [`init`], since other loggers
+/// would not apply it.
+pub fn set_module_level(module: &str, level: Option<LevelFilter>) -> Result<(), NotInitialized> {
+    if !INITIALIZED.load(Ordering::Relaxed) {
+        return Err(NotInitialized);
+    }
+    let mut filters = FILTERS.write().unwrap();
+    match level {
+        Some(level) => filters.modules.insert(module.to_string(), level),
+        None => filters.modules.remove(module),
+    };
+    apply(&filters);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_of_module() {
+        let filters = LogFilters {
+            level: LevelFilter::Info,
+            modules: BTreeMap::from([
+                ("consensus".to_string(), LevelFilter::Debug),
+                ("consensus::peer".to_string(), LevelFilter::Trace),
+            ]),
+        };
+        assert_eq!(filters.level_of("controller"), LevelFilter::Info);
+        assert_eq!(filters.level_of("consensus"), LevelFilter::Debug);
+        assert_eq!(filters.level_of("consensus::raft"), LevelFilter::Debug);
+        assert_eq!(
+            filters.level_of("consensus::peer::codec"),
+            LevelFilter::Trace
+        );
+        assert_eq!(filters.level_of("consensus_raft"), LevelFilter::Info);
+        assert_eq!(filters.max_level(), LevelFilter::Trace);
+        for level in LevelFilter::iter() {
+            assert_eq!(level_filter(level as usize), level);
+        }
+    }
+}
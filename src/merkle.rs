@@ -0,0 +1,295 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Transaction roots of block headers, and Merkle trees of transaction hashes
+//! for inclusion proofs, with the hash of a [`CryptoProvider`].
+//!
+//! [`transactions_root`] is what the controller puts in `transactions_root`
+//! of a header: the hash of the concatenated transaction hashes, which has
+//! no inclusion proofs.
+//!
+//! [`MerkleTree`] is a separate tree and its root is not `transactions_root`.
+//! It follows RFC 6962: leaves are hashed as `0x00 || tx hash` and inner
+//! nodes as `0x01 || left || right`, so a leaf cannot pass for an inner
+//! node, and an odd node at the end of a level moves up unchanged. The root
+//! of no leaves is the hash of empty data.
+
+use crate::crypto::{batch, CryptoProvider, DEFAULT_BATCH_CONCURRENCY};
+use status_code::StatusCode;
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Proves that a leaf is in the tree of a root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub leaf_count: usize,
+    /// Siblings on the path from the leaf up to the root.
+    pub path: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    /// From the leaf hashes up to the root.
+    levels: Vec<Vec<Vec<u8>>>,
+    leaf_count: usize,
+}
+
+impl MerkleTree {
+    pub async fn new<P>(provider: &P, leaves: Vec<Vec<u8>>) -> Result<Self, StatusCode>
+    where
+        P: CryptoProvider + ?Sized,
+    {
+        if leaves.is_empty() {
+            let empty = provider.hash_data(&[]).await?;
+            return Ok(MerkleTree {
+                levels: vec![vec![empty]],
+                leaf_count: 0,
+            });
+        }
+
+        let prefixed: Vec<Vec<u8>> = leaves
+            .iter()
+            .map(|leaf| [&[LEAF_PREFIX], leaf.as_slice()].concat())
+            .collect();
+        let leaf_hashes = batch::hash_batch(provider, &prefixed, DEFAULT_BATCH_CONCURRENCY).await?;
+        let mut levels = vec![leaf_hashes];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let pairs: Vec<Vec<u8>> = level
+                .chunks_exact(2)
+                .map(|pair| [&[NODE_PREFIX], pair[0].as_slice(), pair[1].as_slice()].concat())
+                .collect();
+            let mut parents =
+                batch::hash_batch(provider, &pairs, DEFAULT_BATCH_CONCURRENCY).await?;
+            if level.len() % 2 == 1 {
+                parents.push(level.last().unwrap().clone());
+            }
+            levels.push(parents);
+        }
+        Ok(MerkleTree {
+            levels,
+            leaf_count: leaves.len(),
+        })
+    }
+
+    pub fn root(&self) -> &[u8] {
+        &self.levels.last().unwrap()[0]
+    }
+
+    /// Proof of the leaf at `index`, if there is one.
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.leaf_count {
+            return None;
+        }
+        let mut path = Vec::new();
+        let mut node = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            // an odd node at the end has no sibling and moves up as is
+            if let Some(hash) = level.get(node ^ 1) {
+                path.push(hash.clone());
+            }
+            node /= 2;
+        }
+        Some(MerkleProof {
+            index,
+            leaf_count: self.leaf_count,
+            path,
+        })
+    }
+}
+
+/// The `transactions_root` of a block header with these transactions.
+pub async fn transactions_root<P>(
+    provider: &P,
+    tx_hashes: &[Vec<u8>],
+) -> Result<Vec<u8>, StatusCode>
+where
+    P: CryptoProvider + ?Sized,
+{
+    provider.hash_data(&tx_hashes.concat()).await
+}
+
+/// Root of the [`MerkleTree`] of `leaves`.
+pub async fn merkle_root<P>(provider: &P, leaves: Vec<Vec<u8>>) -> Result<Vec<u8>, StatusCode>
+where
+    P: CryptoProvider + ?Sized,
+{
+    Ok(MerkleTree::new(provider, leaves).await?.root().to_vec())
+}
+
+/// Checks that `leaf` is at `proof.index` of a tree of `proof.leaf_count`
+/// leaves with `root`.
+///
+/// The position fixes which side each sibling is on and how many there are,
+/// so a proof longer or shorter than the path of the leaf fails.
+pub async fn verify_proof<P>(
+    provider: &P,
+    root: &[u8],
+    leaf: &[u8],
+    proof: &MerkleProof,
+) -> Result<bool, StatusCode>
+where
+    P: CryptoProvider + ?Sized,
+{
+    if proof.index >= proof.leaf_count {
+        return Ok(false);
+    }
+    let mut node = proof.index;
+    let mut last = proof.leaf_count - 1;
+    let mut hash = provider.hash_data(&[&[LEAF_PREFIX], leaf].concat()).await?;
+    for sibling in &proof.path {
+        if last == 0 {
+            return Ok(false);
+        }
+        if node & 1 == 1 || node == last {
+            hash = provider
+                .hash_data(&[&[NODE_PREFIX], sibling.as_slice(), &hash].concat())
+                .await?;
+            // skip the levels where the node moved up without a sibling
+            while node & 1 == 0 && node != 0 {
+                node /= 2;
+                last /= 2;
+            }
+        } else {
+            hash = provider
+                .hash_data(&[&[NODE_PREFIX], hash.as_slice(), sibling].concat())
+                .await?;
+        }
+        node /= 2;
+        last /= 2;
+    }
+    Ok(last == 0 && hash == root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::mock::MockCrypto;
+
+    #[tokio::test]
+    async fn prove_every_leaf() {
+        // hashes to the data itself, to show the shape of the tree
+        let crypto = MockCrypto::new();
+        assert_eq!(
+            merkle_root(&crypto, vec![]).await.unwrap(),
+            Vec::<u8>::new()
+        );
+        assert_eq!(
+            merkle_root(&crypto, vec![vec![3]]).await.unwrap(),
+            vec![0, 3]
+        );
+
+        let leaves = vec![vec![1], vec![2], vec![3]];
+        let tree = MerkleTree::new(&crypto, leaves.clone()).await.unwrap();
+        assert_eq!(tree.root(), [1, 1, 0, 1, 0, 2, 0, 3]);
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = tree.proof(index).unwrap();
+            assert!(verify_proof(&crypto, tree.root(), leaf, &proof)
+                .await
+                .unwrap());
+            assert!(!verify_proof(&crypto, tree.root(), &[4], &proof)
+                .await
+                .unwrap());
+        }
+        assert_eq!(tree.proof(2).unwrap().path, vec![vec![1, 0, 1, 0, 2]]);
+        assert_eq!(tree.proof(3), None);
+
+        for leaf_count in 1..10u8 {
+            let leaves: Vec<Vec<u8>> = (0..leaf_count).map(|leaf| vec![leaf]).collect();
+            let tree = MerkleTree::new(&crypto, leaves.clone()).await.unwrap();
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(index).unwrap();
+                assert!(verify_proof(&crypto, tree.root(), leaf, &proof)
+                    .await
+                    .unwrap());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_proof_of_other_depth() {
+        let crypto = MockCrypto::new();
+        let leaves = vec![vec![1], vec![2], vec![3], vec![4]];
+        let tree = MerkleTree::new(&crypto, leaves).await.unwrap();
+        let proof = tree.proof(0).unwrap();
+        assert_eq!(proof.path.len(), 2);
+
+        let short = MerkleProof {
+            path: proof.path[1..].to_vec(),
+            ..proof.clone()
+        };
+        assert!(!verify_proof(&crypto, tree.root(), &[1], &short)
+            .await
+            .unwrap());
+        let fewer_leaves = MerkleProof {
+            leaf_count: 2,
+            ..proof.clone()
+        };
+        assert!(!verify_proof(&crypto, tree.root(), &[1], &fewer_leaves)
+            .await
+            .unwrap());
+
+        // the inner node over leaves 1 and 2, passed off as a leaf
+        let inner = [1, 0, 1, 0, 2];
+        let inner_proof = MerkleProof {
+            index: 0,
+            leaf_count: 2,
+            path: proof.path[1..].to_vec(),
+        };
+        assert!(!verify_proof(&crypto, tree.root(), &inner, &inner_proof)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn root_of_header() {
+        let crypto = MockCrypto::new();
+        let tx_hashes = vec![vec![1, 2], vec![3]];
+        assert_eq!(
+            transactions_root(&crypto, &tx_hashes).await.unwrap(),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[cfg(feature = "eth")]
+    #[tokio::test]
+    async fn root_of_eth_header() {
+        use crate::crypto::EthCrypto;
+        use crate::types::H256;
+        use cita_cloud_proto::blockchain::BlockHeader;
+
+        // keccak256 of the concatenated hashes, computed apart from this crate
+        let header = BlockHeader {
+            height: 1,
+            transactions_root: "41524791bda53e6da2158f10c15e3672835515d6135111d11c7e9880cfcbe573"
+                .parse::<H256>()
+                .unwrap()
+                .to_vec(),
+            ..Default::default()
+        };
+        let tx_hashes = vec![vec![0x11; 32], vec![0x22; 32], vec![0x33; 32]];
+        let crypto = EthCrypto::default();
+        assert_eq!(
+            transactions_root(&crypto, &tx_hashes).await.unwrap(),
+            header.transactions_root
+        );
+        // an empty block has the hash of empty data
+        assert_eq!(
+            transactions_root(&crypto, &Vec::new()).await.unwrap(),
+            crypto.hash_data(&[]).await.unwrap()
+        );
+    }
+}
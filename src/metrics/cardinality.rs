@@ -0,0 +1,118 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounds the values of the labels that clients choose freely: `client`, from
+//! the `client-name` header, and `service` and `method`, from the path.
+//!
+//! ```toml
+//! # only these clients get their own series
+//! client_labels = { allowlist = ["controller", "consensus"] }
+//! # or the first 16 clients seen
+//! client_labels = { cap = 16 }
+//! # methods are listed as `Service/Method`
+//! method_labels = { allowlist = ["crypto.CryptoService/HashData"] }
+//! ```
+
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Value of the labels of requests over the limit.
+pub const OTHER_LABEL: &str = "other";
+
+/// Distinct values of the `client` label kept by default.
+pub const DEFAULT_CLIENT_LABEL_CAP: usize = 64;
+
+/// Distinct `service` and `method` pairs kept by default.
+pub const DEFAULT_METHOD_LABEL_CAP: usize = 256;
+
+/// Which values of a label get their own series, the others are labelled
+/// [`OTHER_LABEL`].
+///
+/// Requests without a `client-name` header are always labelled `unknown`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelLimit {
+    /// Only the listed values.
+    Allowlist(Vec<String>),
+    /// The first values seen, up to this many.
+    Cap(usize),
+}
+
+#[derive(Debug)]
+pub(super) enum LabelGuard {
+    Allowlist(HashSet<String>),
+    Cap {
+        cap: usize,
+        seen: Mutex<HashSet<String>>,
+    },
+}
+
+impl LabelGuard {
+    pub(super) fn new(limit: LabelLimit) -> Self {
+        match limit {
+            LabelLimit::Allowlist(values) => LabelGuard::Allowlist(values.into_iter().collect()),
+            LabelLimit::Cap(cap) => LabelGuard::Cap {
+                cap,
+                seen: Mutex::new(HashSet::new()),
+            },
+        }
+    }
+
+    /// Whether `value` gets its own label, or is folded into [`OTHER_LABEL`].
+    pub(super) fn allows(&self, value: &str) -> bool {
+        if value == super::UNKNOWN_LABEL {
+            return true;
+        }
+        match self {
+            LabelGuard::Allowlist(values) => values.contains(value),
+            LabelGuard::Cap { cap, seen } => {
+                let mut seen = seen.lock().unwrap();
+                if seen.contains(value) {
+                    true
+                } else if seen.len() < *cap {
+                    seen.insert(value.to_string());
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fold_clients_over_limit() {
+        let guard = LabelGuard::new(LabelLimit::Cap(2));
+        assert!(guard.allows("controller"));
+        assert!(guard.allows("consensus"));
+        assert!(!guard.allows("attacker-1"));
+        assert!(guard.allows("controller"));
+        assert!(guard.allows("unknown"));
+
+        #[derive(Deserialize)]
+        struct Config {
+            client_labels: LabelLimit,
+        }
+        let config: Config =
+            toml::from_str("client_labels = { allowlist = [\"controller\"] }").unwrap();
+        let guard = LabelGuard::new(config.client_labels);
+        assert!(guard.allows("controller"));
+        assert!(!guard.allows("consensus"));
+    }
+}
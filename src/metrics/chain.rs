@@ -0,0 +1,160 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Chain level metrics shared by the cita-cloud services.
+//!
+//! Each service sets the gauges it knows about:
+//!
+//! - `chain_block_height`: height of the latest committed block
+//! - `chain_tx_pool_size`: number of transactions waiting in the pool
+//! - `chain_consensus_round`: round of the consensus at the current height
+//! - `chain_peer_count`: number of connected peers
+//! - `chain_syncing`: `1` while the node is syncing blocks, `0` otherwise
+//! - `chain_sync_target_height`: height the node is syncing to
+//!
+//! All of them carry a `service` label, e.g. `controller`, so that one
+//! dashboard can select the same gauge across services. A gauge is only
+//! exported once it has been set, so gauges a service does not know about
+//! are absent rather than `0`.
+
+use super::{register_all, twice};
+use prometheus::{default_registry, IntGaugeVec, Opts, Registry};
+
+/// A gauge with only the `service` label, which has no series until it is set.
+#[derive(Debug, Clone)]
+struct ChainGauge(IntGaugeVec);
+
+impl ChainGauge {
+    fn set(&self, value: i64) {
+        self.0.with_label_values(&[]).set(value);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainMetrics {
+    block_height: ChainGauge,
+    tx_pool_size: ChainGauge,
+    consensus_round: ChainGauge,
+    peer_count: ChainGauge,
+    syncing: ChainGauge,
+    sync_target_height: ChainGauge,
+}
+
+impl ChainMetrics {
+    /// Registers the chain metrics of `service` in the default registry.
+    pub fn new(service: &str) -> prometheus::Result<Self> {
+        Self::with_registry(service, default_registry())
+    }
+
+    /// Registers the chain metrics of `service` in `registry`.
+    pub fn with_registry(service: &str, registry: &Registry) -> prometheus::Result<Self> {
+        let gauge = |name: &str, help: &str| {
+            let gauge =
+                IntGaugeVec::new(Opts::new(name, help).const_label("service", service), &[])?;
+            Ok::<_, prometheus::Error>(ChainGauge(gauge))
+        };
+
+        let metrics = ChainMetrics {
+            block_height: gauge("chain_block_height", "height of the latest committed block")?,
+            tx_pool_size: gauge("chain_tx_pool_size", "number of transactions in the pool")?,
+            consensus_round: gauge(
+                "chain_consensus_round",
+                "consensus round at the current height",
+            )?,
+            peer_count: gauge("chain_peer_count", "number of connected peers")?,
+            syncing: gauge("chain_syncing", "whether the node is syncing blocks")?,
+            sync_target_height: gauge("chain_sync_target_height", "height the node is syncing to")?,
+        };
+        register_all(
+            registry,
+            vec![
+                twice(&metrics.block_height.0),
+                twice(&metrics.tx_pool_size.0),
+                twice(&metrics.consensus_round.0),
+                twice(&metrics.peer_count.0),
+                twice(&metrics.syncing.0),
+                twice(&metrics.sync_target_height.0),
+            ],
+        )?;
+        Ok(metrics)
+    }
+
+    pub fn set_block_height(&self, height: u64) {
+        self.block_height.set(height as i64);
+    }
+
+    pub fn set_tx_pool_size(&self, size: usize) {
+        self.tx_pool_size.set(size as i64);
+    }
+
+    pub fn set_consensus_round(&self, round: u64) {
+        self.consensus_round.set(round as i64);
+    }
+
+    pub fn set_peer_count(&self, count: usize) {
+        self.peer_count.set(count as i64);
+    }
+
+    /// Sets whether the node is syncing, and the height it syncs to.
+    ///
+    /// The target height is kept when syncing stops, so that it can be
+    /// compared with `chain_block_height`.
+    pub fn set_syncing(&self, syncing: bool, target_height: u64) {
+        self.syncing.set(syncing as i64);
+        self.sync_target_height.set(target_height as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_chain_metrics() {
+        let registry = Registry::new();
+        let metrics = ChainMetrics::with_registry("controller", &registry).unwrap();
+        metrics.set_block_height(100);
+        metrics.set_syncing(true, 120);
+        assert!(ChainMetrics::with_registry("controller", &registry).is_err());
+
+        // the gauges that were never set are not exported
+        let families = registry.gather();
+        assert_eq!(families.len(), 3);
+        let height = families
+            .iter()
+            .find(|mf| mf.get_name() == "chain_block_height")
+            .unwrap();
+        let metric = &height.get_metric()[0];
+        assert_eq!(metric.get_gauge().get_value(), 100.0);
+        assert_eq!(metric.get_label()[0].get_name(), "service");
+        assert_eq!(metric.get_label()[0].get_value(), "controller");
+    }
+
+    #[test]
+    fn register_all_or_nothing() {
+        let registry = Registry::new();
+        // same as the chain's, since a name keeps its labels once registered
+        let taken = IntGaugeVec::new(
+            Opts::new("chain_syncing", "whether the node is syncing blocks")
+                .const_label("service", "network"),
+            &[],
+        )
+        .unwrap();
+        registry.register(Box::new(taken.clone())).unwrap();
+        assert!(ChainMetrics::with_registry("network", &registry).is_err());
+
+        registry.unregister(Box::new(taken)).unwrap();
+        assert!(ChainMetrics::with_registry("network", &registry).is_ok());
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Metrics for outgoing calls made by the `crypto`, `storage` and `network` helpers.
+//!
+//! Every helper call is recorded in:
+//!
+//! - `grpc_client_handling_ms`: histogram of call latencies in milliseconds
+//! - `grpc_client_handled_total`: counter of completed calls
+//! - `grpc_client_errors_total`: counter of calls that did not return `Success`
+//!
+//! labelled with `target` (the service called, e.g. `crypto`), `method`
+//! (the helper, e.g. `hash_data`) and `code` (the returned [`StatusCode`]).
+//!
+//! The helpers call through [`RetryClient`](cita_cloud_proto::retry::RetryClient),
+//! so the latency of a call includes its retries, and a call that ran out of
+//! retries is counted with the `*ServerNotReady` code of its target.
+
+use super::{register_all, twice};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{
+    default_registry, exponential_buckets, HistogramOpts, HistogramVec, IntCounterVec, Opts,
+    Registry,
+};
+use status_code::StatusCode;
+use std::future::Future;
+use std::sync::RwLock;
+use std::time::Instant;
+
+lazy_static! {
+    static ref CLIENT_METRICS: RwLock<Option<ClientMetrics>> = RwLock::new(None);
+}
+
+#[derive(Clone)]
+struct ClientMetrics {
+    latency: HistogramVec,
+    handled: IntCounterVec,
+    errors: IntCounterVec,
+}
+
+impl ClientMetrics {
+    fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let labels = &["target", "method", "code"];
+        let metrics = ClientMetrics {
+            latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "grpc_client_handling_ms",
+                    "outgoing call latencies in milliseconds(ms)",
+                )
+                // 1ms to ~16s
+                .buckets(exponential_buckets(1.0, 2.0, 15)?),
+                labels,
+            )?,
+            handled: IntCounterVec::new(
+                Opts::new(
+                    "grpc_client_handled_total",
+                    "total number of completed outgoing calls",
+                ),
+                labels,
+            )?,
+            errors: IntCounterVec::new(
+                Opts::new(
+                    "grpc_client_errors_total",
+                    "total number of outgoing calls that did not succeed",
+                ),
+                labels,
+            )?,
+        };
+
+        register_all(
+            registry,
+            vec![
+                twice(&metrics.latency),
+                twice(&metrics.handled),
+                twice(&metrics.errors),
+            ],
+        )?;
+        Ok(metrics)
+    }
+}
+
+/// Registers the client metrics in `registry` instead of the default one.
+///
+/// The helpers are shared by the whole process, so this has to be called
+/// before the first helper call and fails if the metrics are already registered.
+pub fn register(registry: &Registry) -> prometheus::Result<()> {
+    let mut write = CLIENT_METRICS.write().unwrap();
+    if write.is_some() {
+        return Err(prometheus::Error::AlreadyReg);
+    }
+    *write = Some(ClientMetrics::register(registry)?);
+    Ok(())
+}
+
+/// Returns the client metrics, registering them in the default registry on first use.
+fn client_metrics() -> Option<ClientMetrics> {
+    if let Some(metrics) = CLIENT_METRICS.read().unwrap().as_ref() {
+        return Some(metrics.clone());
+    }
+
+    let mut write = CLIENT_METRICS.write().unwrap();
+    if write.is_none() {
+        match ClientMetrics::register(default_registry()) {
+            Ok(metrics) => {
+                info!("register grpc client metrics succeeded");
+                *write = Some(metrics);
+            }
+            Err(e) => {
+                warn!(
+                    "register grpc client metrics failed with error: {}, ignored metrics",
+                    e
+                );
+            }
+        }
+    }
+    write.clone()
+}
+
+/// Outcome of a helper call, as reported in the `code` label.
+pub trait CallStatus {
+    fn status(&self) -> StatusCode;
+}
+
+impl CallStatus for StatusCode {
+    fn status(&self) -> StatusCode {
+        *self
+    }
+}
+
+impl<T> CallStatus for Result<T, StatusCode> {
+    fn status(&self) -> StatusCode {
+        match self {
+            Ok(_) => StatusCode::Success,
+            Err(status) => *status,
+        }
+    }
+}
+
+/// Runs `call` and records its latency and outcome against `target` and `method`.
+pub async fn observe_client_call<F>(target: &str, method: &str, call: F) -> F::Output
+where
+    F: Future,
+    F::Output: CallStatus,
+{
+    let started = Instant::now();
+    let output = call.await;
+
+    if let Some(metrics) = client_metrics() {
+        let elapsed = started.elapsed().as_secs_f64() * 1000f64;
+        let status = output.status();
+        let code = format!("{:?}", status);
+        let labels = [target, method, code.as_str()];
+
+        metrics.latency.with_label_values(&labels).observe(elapsed);
+        metrics.handled.with_label_values(&labels).inc();
+        if status != StatusCode::Success {
+            metrics.errors.with_label_values(&labels).inc();
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter_value(registry: &Registry, name: &str) -> Vec<(String, f64)> {
+        registry
+            .gather()
+            .into_iter()
+            .filter(|mf| mf.get_name() == name)
+            .flat_map(|mf| mf.get_metric().to_vec())
+            .map(|m| {
+                let labels: Vec<&str> = m.get_label().iter().map(|l| l.get_value()).collect();
+                (labels.join(","), m.get_counter().get_value())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn register_all_or_nothing() {
+        let registry = Registry::new();
+        // same as the client's, since a name keeps its labels once registered
+        let taken = IntCounterVec::new(
+            Opts::new(
+                "grpc_client_errors_total",
+                "total number of outgoing calls that did not succeed",
+            ),
+            &["target", "method", "code"],
+        )
+        .unwrap();
+        registry.register(Box::new(taken.clone())).unwrap();
+        assert!(ClientMetrics::register(&registry).is_err());
+
+        registry.unregister(Box::new(taken)).unwrap();
+        assert!(ClientMetrics::register(&registry).is_ok());
+    }
+
+    #[tokio::test]
+    async fn record_calls() {
+        let registry = Registry::new();
+        register(&registry).unwrap();
+        assert!(register(&registry).is_err());
+
+        let result: Result<(), StatusCode> = observe_client_call("crypto", "hash_data", async {
+            Err(StatusCode::CryptoServerNotReady)
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(
+            counter_value(&registry, "grpc_client_errors_total"),
+            [("CryptoServerNotReady,hash_data,crypto".to_string(), 1.0)]
+        );
+    }
+}
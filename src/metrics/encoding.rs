@@ -0,0 +1,329 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exposition formats served by the exporter, chosen from the `Accept` header.
+//!
+//! The prometheus client does not record exemplars or when a series was
+//! created, so the OpenMetrics output has no exemplars nor `_created`
+//! samples, which the format allows; it is otherwise a complete OpenMetrics
+//! 1.0 exposition, including the `# EOF` marker.
+//!
+//! OpenMetrics requires the samples of a counter to end in `_total`, so a
+//! counter registered without that suffix, e.g. `requests`, is exposed as
+//! `requests` in the text format but as `requests_total` in OpenMetrics.
+//! Prometheus asks for OpenMetrics first, so queries and dashboards on such
+//! counters have to use the `_total` name; counters of this crate already
+//! end in `_total`.
+
+use flate2::{write::GzEncoder, Compression};
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::{Encoder, ProtobufEncoder, TextEncoder};
+use std::fmt::Display;
+use std::io::{self, Write};
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Exposition format of a scrape response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    OpenMetrics,
+    Protobuf,
+}
+
+impl Format {
+    /// Picks the format with the highest quality in an `Accept` header.
+    ///
+    /// Ties are broken by the order in the header, and anything unsupported
+    /// or missing falls back to the prometheus text format.
+    pub fn negotiate(accept: Option<&str>) -> Format {
+        let mut best = (Format::Text, 0f32);
+        for (media_type, params, q) in media_ranges(accept.unwrap_or_default()) {
+            let format = match media_type.as_str() {
+                "application/openmetrics-text" => Format::OpenMetrics,
+                "application/vnd.google.protobuf"
+                    if params
+                        .iter()
+                        .any(|(k, v)| k == "proto" && v == "io.prometheus.client.MetricFamily") =>
+                {
+                    Format::Protobuf
+                }
+                "text/plain" | "text/*" | "*/*" => Format::Text,
+                _ => continue,
+            };
+            if q > best.1 {
+                best = (format, q);
+            }
+        }
+        best.0
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Text => prometheus::TEXT_FORMAT,
+            Format::OpenMetrics => OPENMETRICS_FORMAT,
+            Format::Protobuf => prometheus::PROTOBUF_FORMAT,
+        }
+    }
+
+    pub fn encode(&self, metric_families: &[MetricFamily]) -> prometheus::Result<Vec<u8>> {
+        let mut buffer = vec![];
+        match self {
+            Format::Text => TextEncoder::new().encode(metric_families, &mut buffer)?,
+            Format::OpenMetrics => OpenMetricsEncoder.encode(metric_families, &mut buffer)?,
+            Format::Protobuf => ProtobufEncoder::new().encode(metric_families, &mut buffer)?,
+        }
+        Ok(buffer)
+    }
+}
+
+/// Whether an `Accept-Encoding` header allows a gzip response.
+pub fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    media_ranges(accept_encoding.unwrap_or_default())
+        .any(|(coding, _, q)| (coding == "gzip" || coding == "*") && q > 0.0)
+}
+
+pub fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Parses `type; key=value; q=0.5, ...` into lowercased types, their
+/// parameters and quality.
+fn media_ranges(header: &str) -> impl Iterator<Item = (String, Vec<(String, String)>, f32)> + '_ {
+    header.split(',').filter_map(|range| {
+        let mut parts = range.split(';').map(str::trim);
+        let media_type = parts.next().filter(|t| !t.is_empty())?.to_lowercase();
+        let mut q = 1f32;
+        let mut params = Vec::new();
+        for param in parts {
+            if let Some((key, value)) = param.split_once('=') {
+                let key = key.trim().to_lowercase();
+                let value = value.trim().trim_matches('"').to_string();
+                if key == "q" {
+                    q = value.parse().unwrap_or(0.0);
+                } else {
+                    params.push((key, value));
+                }
+            }
+        }
+        Some((media_type, params, q))
+    })
+}
+
+/// Encodes metric families in the OpenMetrics text format.
+#[derive(Debug, Default)]
+pub struct OpenMetricsEncoder;
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(&self, mfs: &[MetricFamily], writer: &mut W) -> prometheus::Result<()> {
+        for mf in mfs {
+            let metric_type = mf.get_field_type();
+            // counters are named without the `_total` suffix of their samples
+            let name = match metric_type {
+                MetricType::COUNTER => mf
+                    .get_name()
+                    .strip_suffix("_total")
+                    .unwrap_or_else(|| mf.get_name()),
+                _ => mf.get_name(),
+            };
+            let type_name = match metric_type {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::HISTOGRAM => "histogram",
+                MetricType::SUMMARY => "summary",
+                MetricType::UNTYPED => "unknown",
+            };
+
+            writeln!(writer, "# TYPE {} {}", name, type_name)?;
+            if !mf.get_help().is_empty() {
+                writeln!(writer, "# HELP {} {}", name, escape(mf.get_help()))?;
+            }
+
+            for m in mf.get_metric() {
+                match metric_type {
+                    MetricType::COUNTER => {
+                        let value = format_float(m.get_counter().get_value());
+                        write_sample(writer, name, "_total", m, None, value)?;
+                    }
+                    MetricType::GAUGE => {
+                        let value = format_float(m.get_gauge().get_value());
+                        write_sample(writer, name, "", m, None, value)?;
+                    }
+                    MetricType::HISTOGRAM => {
+                        let h = m.get_histogram();
+                        let mut inf_seen = false;
+                        for b in h.get_bucket() {
+                            let upper_bound = b.get_upper_bound();
+                            inf_seen |= upper_bound == f64::INFINITY;
+                            let le = format_float(upper_bound);
+                            let count = b.get_cumulative_count();
+                            write_sample(writer, name, "_bucket", m, Some(("le", &le)), count)?;
+                        }
+                        let count = h.get_sample_count();
+                        if !inf_seen {
+                            write_sample(writer, name, "_bucket", m, Some(("le", "+Inf")), count)?;
+                        }
+                        let sum = format_float(h.get_sample_sum());
+                        write_sample(writer, name, "_sum", m, None, sum)?;
+                        write_sample(writer, name, "_count", m, None, count)?;
+                    }
+                    MetricType::SUMMARY => {
+                        let s = m.get_summary();
+                        for q in s.get_quantile() {
+                            let quantile = format_float(q.get_quantile());
+                            let label = Some(("quantile", quantile.as_str()));
+                            write_sample(writer, name, "", m, label, format_float(q.get_value()))?;
+                        }
+                        let sum = format_float(s.get_sample_sum());
+                        write_sample(writer, name, "_sum", m, None, sum)?;
+                        let count = s.get_sample_count();
+                        write_sample(writer, name, "_count", m, None, count)?;
+                    }
+                    MetricType::UNTYPED => {
+                        let value = format_float(m.get_untyped().get_value());
+                        write_sample(writer, name, "", m, None, value)?;
+                    }
+                }
+            }
+        }
+        writer.write_all(b"# EOF\n")?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+fn write_sample<W: Write>(
+    writer: &mut W,
+    name: &str,
+    suffix: &str,
+    m: &Metric,
+    extra_label: Option<(&str, &str)>,
+    value: impl Display,
+) -> io::Result<()> {
+    write!(writer, "{}{}", name, suffix)?;
+    write_labels(writer, m.get_label(), extra_label)?;
+    write!(writer, " {}", value)?;
+    if m.get_timestamp_ms() != 0 {
+        // OpenMetrics timestamps are in seconds
+        write!(writer, " {}", m.get_timestamp_ms() as f64 / 1000f64)?;
+    }
+    writer.write_all(b"\n")
+}
+
+fn write_labels<W: Write>(
+    writer: &mut W,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, &str)>,
+) -> io::Result<()> {
+    let mut pairs = labels
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .chain(extra_label)
+        .peekable();
+    if pairs.peek().is_none() {
+        return Ok(());
+    }
+
+    writer.write_all(b"{")?;
+    for (i, (name, value)) in pairs.enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        write!(writer, "{}=\"{}\"", name, escape(value))?;
+    }
+    writer.write_all(b"}")
+}
+
+fn format_float(f: f64) -> String {
+    if f == f64::INFINITY {
+        "+Inf".to_string()
+    } else if f == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else if f.is_nan() {
+        "NaN".to_string()
+    } else {
+        format!("{:?}", f)
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Histogram, HistogramOpts, IntCounterVec, Opts, Registry};
+
+    #[test]
+    fn negotiate_format() {
+        assert_eq!(Format::negotiate(None), Format::Text);
+        assert_eq!(
+            Format::negotiate(Some(
+                "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"
+            )),
+            Format::OpenMetrics
+        );
+        assert_eq!(
+            Format::negotiate(Some(
+                "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3"
+            )),
+            Format::Protobuf
+        );
+        assert_eq!(Format::negotiate(Some("application/json")), Format::Text);
+        assert!(accepts_gzip(Some("gzip, deflate")));
+        assert!(!accepts_gzip(Some("gzip;q=0, deflate")));
+        assert!(!accepts_gzip(None));
+    }
+
+    #[test]
+    fn encode_openmetrics() {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(
+            Opts::new("requests_total", "handled \"requests\""),
+            &["method"],
+        )
+        .unwrap();
+        let histogram =
+            Histogram::with_opts(HistogramOpts::new("latency", "latency").buckets(vec![1.0]))
+                .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        counter.with_label_values(&["Exec"]).inc();
+        histogram.observe(0.5);
+
+        let output = Format::OpenMetrics.encode(&registry.gather()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "# TYPE latency histogram
+# HELP latency latency
+latency_bucket{le=\"1.0\"} 1
+latency_bucket{le=\"+Inf\"} 1
+latency_sum 0.5
+latency_count 1
+# TYPE requests counter
+# HELP requests handled \\\"requests\\\"
+requests_total{method=\"Exec\"} 1.0
+# EOF
+"
+        );
+    }
+}
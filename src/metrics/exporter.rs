@@ -0,0 +1,784 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP server exporting the gathered metrics to prometheus, along with
+//! health, readiness and build information endpoints.
+//!
+//! The exporter is configured by an [`ExporterConfig`], which services can
+//! embed in their own toml section:
+//!
+//! ```toml
+//! [controller.metrics_exporter]
+//! bind_addr = "127.0.0.1"
+//! port = 60004
+//! # serve `/loglevel`
+//! log_admin = true
+//! # serve `/debug/pprof/profile`, with the `pprof` feature
+//! profile_admin = true
+//!
+//! [controller.metrics_exporter.tls]
+//! cert_path = "/etc/cita-cloud/metrics.crt"
+//! key_path = "/etc/cita-cloud/metrics.key"
+//!
+//! [controller.metrics_exporter.basic_auth]
+//! username = "prometheus"
+//! password = "secret"
+//! ```
+
+use super::encoding::{accepts_gzip, gzip, Format};
+use crate::logging;
+use futures::Future;
+use hyper::{
+    header::{
+        ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, VARY,
+        WWW_AUTHENTICATE,
+    },
+    server::conn::Http,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{info, warn, LevelFilter};
+use percent_encoding::percent_decode_str;
+use prometheus::{default_registry, Registry};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Connections that have not completed their TLS handshake by then are dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExporterConfig {
+    /// Address to listen on, all interfaces by default.
+    #[serde(default = "default_bind_addr")]
+    pub bind_addr: IpAddr,
+    pub port: u16,
+    /// Serve over https when set.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Require `Authorization: Bearer <token>` when set.
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+    /// Require `Authorization: Basic ...` with these credentials when set.
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuthConfig>,
+    /// Serve `/loglevel` to read and change log levels at runtime.
+    ///
+    /// Only served with authentication or on a loopback `bind_addr`.
+    #[serde(default)]
+    pub log_admin: bool,
+    /// Serve `/debug/pprof/profile` with the `pprof` feature.
+    ///
+    /// Only served with authentication or on a loopback `bind_addr`.
+    #[serde(default)]
+    pub profile_admin: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain.
+    pub cert_path: String,
+    /// PEM encoded PKCS#8 or RSA private key.
+    pub key_path: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BasicAuthConfig {
+    pub username: String,
+    pub password: String,
+}
+
+fn default_bind_addr() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+impl ExporterConfig {
+    /// Plain http on all interfaces without authentication.
+    pub fn new(port: u16) -> Self {
+        ExporterConfig {
+            bind_addr: default_bind_addr(),
+            port,
+            tls: None,
+            bearer_token: None,
+            basic_auth: None,
+            log_admin: false,
+            profile_admin: false,
+        }
+    }
+
+    fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_addr, self.port)
+    }
+
+    /// Values of the `Authorization` header that are accepted, empty if
+    /// authentication is disabled.
+    fn authorizations(&self) -> Vec<String> {
+        let mut authorizations = Vec::new();
+        if let Some(token) = &self.bearer_token {
+            authorizations.push(format!("Bearer {}", token));
+        }
+        if let Some(basic) = &self.basic_auth {
+            let credentials = format!("{}:{}", basic.username, basic.password);
+            authorizations.push(format!("Basic {}", base64::encode(credentials)));
+        }
+        authorizations
+    }
+}
+
+/// Serves https connections until `shutdown` completes and the open
+/// connections have finished.
+///
+/// Each connection does its TLS handshake in its own task, so a slow or
+/// stalled client only holds up itself, until [`TLS_HANDSHAKE_TIMEOUT`].
+async fn serve_tls(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    handler: Handler,
+    shutdown: impl Future<Output = ()>,
+) {
+    let (stop, stopped) = watch::channel(());
+    tokio::pin!(shutdown);
+    loop {
+        let stream = tokio::select! {
+            _ = &mut shutdown => break,
+            conn = listener.accept() => match conn {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("accept metrics connection failed: {}", e);
+                    // e.g. out of file descriptors, which a retry won't fix at once
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+        };
+        let acceptor = acceptor.clone();
+        let handler = handler.clone();
+        let mut stopped = stopped.clone();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        warn!("tls handshake of metrics connection failed: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("tls handshake of metrics connection timed out");
+                        return;
+                    }
+                };
+            let service = service_fn(move |req| handler.clone().serve_req(req));
+            let conn = Http::new().serve_connection(stream, service);
+            tokio::pin!(conn);
+            let served = tokio::select! {
+                served = conn.as_mut() => served,
+                _ = stopped.changed() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = served {
+                warn!("serve metrics connection failed: {}", e);
+            }
+        });
+    }
+    drop(listener);
+    drop(stopped);
+    // wakes the connections, then waits for their receivers to be dropped
+    let _ = stop.send(());
+    stop.closed().await;
+}
+
+fn load_tls_config(tls: &TlsConfig) -> io::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&tls.cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = load_private_key(&tls.key_path)?;
+
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn load_private_key(path: impl AsRef<Path>) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => continue,
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "no private key found",
+    ))
+}
+
+/// Called by `/readyz`, returns whether the service is ready to handle requests.
+pub type ReadinessCheck = Arc<dyn Fn() -> bool + Send + Sync>;
+
+/// Reported by `/version`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BuildInfo {
+    /// Version of the service crate, e.g. `env!("CARGO_PKG_VERSION")`.
+    pub version: String,
+    pub git_commit: String,
+    pub chain_id: String,
+    pub node_address: String,
+}
+
+#[derive(Serialize)]
+struct VersionResponse<'a> {
+    #[serde(flatten)]
+    build_info: &'a BuildInfo,
+    cloud_util_version: &'static str,
+}
+
+/// Read and written by `/loglevel`.
+#[derive(Serialize)]
+struct LogLevelResponse {
+    level: String,
+    modules: BTreeMap<String, String>,
+}
+
+impl LogLevelResponse {
+    fn current() -> Self {
+        let filters = logging::log_filters();
+        LogLevelResponse {
+            level: filters.level.to_string().to_lowercase(),
+            modules: filters
+                .modules
+                .into_iter()
+                .map(|(module, level)| (module, level.to_string().to_lowercase()))
+                .collect(),
+        }
+    }
+}
+
+/// HTTP server exposing `/metrics`, `/healthz`, `/readyz` and `/version`.
+///
+/// The probe endpoints `/healthz` and `/readyz` do not require
+/// authentication, so that they can be used by kubernetes directly.
+///
+/// With `log_admin` set in its config, and either authentication or a
+/// loopback `bind_addr`, it also serves `/loglevel`, see [`crate::logging`]:
+///
+/// - `GET /loglevel`: the default level and the levels of modules, as json
+/// - `PUT /loglevel?level=debug`: sets the default level
+/// - `PUT /loglevel?module=consensus_raft&level=debug`: sets a module level
+/// - `DELETE /loglevel?module=consensus_raft`: removes a module level
+///
+/// With the `pprof` feature and `profile_admin` set, under the same
+/// condition, it serves CPU profiles on `/debug/pprof/profile?seconds=N`,
+/// as a pprof protobuf or with `format=flamegraph` as an svg flamegraph.
+#[derive(Clone)]
+pub struct MetricsExporter {
+    config: ExporterConfig,
+    handler: Handler,
+}
+
+/// State shared by the connections of one exporter.
+#[derive(Clone)]
+struct Handler {
+    registry: Registry,
+    authorizations: Arc<Vec<String>>,
+    readiness: Option<ReadinessCheck>,
+    build_info: Arc<BuildInfo>,
+    log_admin: bool,
+    profile_admin: bool,
+}
+
+impl MetricsExporter {
+    pub fn new(config: ExporterConfig, registry: Registry) -> Self {
+        let authorizations = Arc::new(config.authorizations());
+        // anyone who can reach them could otherwise turn on trace logging,
+        // or keep the node busy profiling
+        let guarded = !authorizations.is_empty() || config.bind_addr.is_loopback();
+        let log_admin = config.log_admin && guarded;
+        if config.log_admin && !log_admin {
+            warn!(
+                "/loglevel is not served on {} without authentication",
+                config.bind_addr
+            );
+        }
+        let profile_admin = config.profile_admin && guarded;
+        if config.profile_admin && !profile_admin {
+            warn!(
+                "/debug/pprof/profile is not served on {} without authentication",
+                config.bind_addr
+            );
+        }
+        MetricsExporter {
+            config,
+            handler: Handler {
+                registry,
+                authorizations,
+                readiness: None,
+                build_info: Arc::new(BuildInfo::default()),
+                log_admin,
+                profile_admin,
+            },
+        }
+    }
+
+    /// Reports ready on `/readyz` only while `check` returns true.
+    ///
+    /// Without a check the service is always reported ready.
+    pub fn readiness(mut self, check: impl Fn() -> bool + Send + Sync + 'static) -> Self {
+        self.handler.readiness = Some(Arc::new(check));
+        self
+    }
+
+    pub fn build_info(mut self, build_info: BuildInfo) -> Self {
+        self.handler.build_info = Arc::new(build_info);
+        self
+    }
+
+    /// Serves until `shutdown` completes, then drains the open connections.
+    pub async fn run(
+        self,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let handler = self.handler;
+        #[cfg(feature = "process-metrics")]
+        if let Err(e) = super::register_process_metrics(&handler.registry) {
+            warn!("register process metrics failed with error: {}", e);
+        }
+        let addr = self.config.socket_addr();
+        match &self.config.tls {
+            Some(tls) => {
+                let acceptor = TlsAcceptor::from(Arc::new(load_tls_config(tls)?));
+                let listener = TcpListener::bind(addr).await?;
+                info!("exporting metrics to https://{}/metrics", addr);
+
+                serve_tls(listener, acceptor, handler, shutdown).await;
+            }
+            None => {
+                let make_svc = make_service_fn(move |_conn| {
+                    let handler = handler.clone();
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |req| handler.clone().serve_req(req)))
+                    }
+                });
+                let server = Server::try_bind(&addr)?.serve(make_svc);
+                info!(
+                    "exporting metrics to http://{}/metrics",
+                    server.local_addr()
+                );
+
+                server.with_graceful_shutdown(shutdown).await?;
+            }
+        }
+        info!("metrics exporter on {} stopped", addr);
+
+        Ok(())
+    }
+}
+
+impl Handler {
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        if self.authorizations.is_empty() {
+            return true;
+        }
+        match req.headers().get(AUTHORIZATION) {
+            Some(value) => self
+                .authorizations
+                .iter()
+                .any(|expected| constant_time_eq(expected.as_bytes(), value.as_bytes())),
+            None => false,
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        match &self.readiness {
+            Some(check) => check(),
+            None => true,
+        }
+    }
+
+    fn serve_metrics(&self, req: &Request<Body>) -> Response<Body> {
+        let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+        let format = Format::negotiate(header(ACCEPT));
+        let metric_families = self.registry.gather();
+        let buffer = match format.encode(&metric_families) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                warn!("encode metrics failed: {}", e);
+                return text_response(StatusCode::INTERNAL_SERVER_ERROR, "encode metrics failed");
+            }
+        };
+
+        let response = Response::builder()
+            .status(200)
+            .header(CONTENT_TYPE, format.content_type())
+            // for caches, the format and the compression depend on these
+            .header(VARY, "Accept, Accept-Encoding");
+        if accepts_gzip(header(ACCEPT_ENCODING)) {
+            match gzip(&buffer) {
+                Ok(compressed) => {
+                    return response
+                        .header(CONTENT_ENCODING, "gzip")
+                        .body(Body::from(compressed))
+                        .unwrap()
+                }
+                Err(e) => warn!("compress metrics failed: {}", e),
+            }
+        }
+        response.body(Body::from(buffer)).unwrap()
+    }
+
+    fn serve_log_level(&self, req: &Request<Body>) -> Response<Body> {
+        let query: HashMap<&str, Cow<str>> = req
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (key, percent_decode_str(value).decode_utf8_lossy()))
+            .collect();
+        let module = query.get("module").map(|module| module.as_ref());
+        let level = match query.get("level").map(|level| level.parse::<LevelFilter>()) {
+            Some(Ok(level)) => Some(level),
+            Some(Err(_)) => {
+                return text_response(
+                    StatusCode::BAD_REQUEST,
+                    "level must be one of off, error, warn, info, debug, trace\n",
+                )
+            }
+            None => None,
+        };
+
+        let changed = match (req.method(), module, level) {
+            (&Method::GET, _, _) => Ok(()),
+            (&Method::PUT, None, Some(level)) => {
+                info!("log level set to {}", level);
+                logging::set_level(level);
+                Ok(())
+            }
+            (&Method::PUT, Some(module), Some(level)) => {
+                logging::set_module_level(module, Some(level))
+                    .map(|()| info!("log level of {} set to {}", module, level))
+            }
+            (&Method::DELETE, Some(module), None) => logging::set_module_level(module, None)
+                .map(|()| info!("log level of {} removed", module)),
+            _ => {
+                return text_response(
+                    StatusCode::BAD_REQUEST,
+                    "usage: PUT /loglevel?[module=<module>&]level=<level>, DELETE /loglevel?module=<module>\n",
+                )
+            }
+        };
+        if changed.is_err() {
+            return text_response(
+                StatusCode::NOT_IMPLEMENTED,
+                "module log levels need the logger installed by logging::init\n",
+            );
+        }
+
+        Response::builder()
+            .status(200)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_vec(&LogLevelResponse::current()).unwrap(),
+            ))
+            .unwrap()
+    }
+
+    async fn serve_req(self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let response = match (req.method(), req.uri().path()) {
+            (&Method::GET, "/healthz") => text_response(StatusCode::OK, "ok"),
+            (&Method::GET, "/readyz") => {
+                if self.is_ready() {
+                    text_response(StatusCode::OK, "ok")
+                } else {
+                    text_response(StatusCode::SERVICE_UNAVAILABLE, "not ready")
+                }
+            }
+            _ if !self.is_authorized(&req) => Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(WWW_AUTHENTICATE, "Basic realm=\"metrics\"")
+                .body(Body::empty())
+                .unwrap(),
+            (&Method::GET, "/metrics") => self.serve_metrics(&req),
+            (_, "/loglevel") if self.log_admin => self.serve_log_level(&req),
+            #[cfg(feature = "pprof")]
+            (&Method::GET, "/debug/pprof/profile") if self.profile_admin => {
+                super::profile::serve_profile(&req).await
+            }
+            (&Method::GET, "/version") => {
+                let version = VersionResponse {
+                    build_info: &self.build_info,
+                    cloud_util_version: env!("CARGO_PKG_VERSION"),
+                };
+                Response::builder()
+                    .status(200)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&version).unwrap()))
+                    .unwrap()
+            }
+            _ => text_response(
+                StatusCode::NOT_FOUND,
+                if self.log_admin {
+                    "available endpoints: /metrics, /healthz, /readyz, /version, /loglevel\n"
+                } else {
+                    "available endpoints: /metrics, /healthz, /readyz, /version\n"
+                },
+            ),
+        };
+
+        Ok(response)
+    }
+}
+
+fn text_response(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub async fn run_metrics_exporter(
+    port: u16,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    run_metrics_exporter_with_registry(port, default_registry().clone()).await
+}
+
+/// Serves the metrics gathered from `registry` instead of the default one.
+pub async fn run_metrics_exporter_with_registry(
+    port: u16,
+    registry: Registry,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    run_metrics_exporter_with_config(ExporterConfig::new(port), registry).await
+}
+
+/// Serves the metrics gathered from `registry` as described by `config`.
+pub async fn run_metrics_exporter_with_config(
+    config: ExporterConfig,
+    registry: Registry,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    run_metrics_exporter_with_shutdown(config, registry, futures::future::pending()).await
+}
+
+/// Like [`run_metrics_exporter_with_config`], but stops accepting connections
+/// once `shutdown` completes and returns after the open ones are drained.
+///
+/// [`crate::signal::shutdown_signal`] can be used to stop on `SIGTERM`.
+/// Use [`MetricsExporter`] to also serve readiness and build information.
+pub async fn run_metrics_exporter_with_shutdown(
+    config: ExporterConfig,
+    registry: Registry,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    MetricsExporter::new(config, registry).run(shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct ControllerConfig {
+        metrics_exporter: ExporterConfig,
+    }
+
+    #[test]
+    fn read_exporter_config() {
+        let config: ControllerConfig =
+            crate::common::read_toml("src/example/sample.toml", "controller");
+        let exporter = config.metrics_exporter;
+        assert_eq!(exporter.socket_addr(), "127.0.0.1:60004".parse().unwrap());
+        assert!(exporter.tls.is_none());
+        assert_eq!(
+            exporter.authorizations(),
+            vec!["Bearer e2e3b2f8".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn start_and_stop_exporter() {
+        let mut config = ExporterConfig::new(0);
+        config.bind_addr = "127.0.0.1".parse().unwrap();
+        for _ in 0..3 {
+            let (tx, rx) = futures::channel::oneshot::channel::<()>();
+            let exporter = tokio::spawn(run_metrics_exporter_with_shutdown(
+                config.clone(),
+                Registry::new(),
+                async {
+                    rx.await.ok();
+                },
+            ));
+            tx.send(()).unwrap();
+            assert!(exporter.await.unwrap().is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn probes_skip_authentication() {
+        let mut config = ExporterConfig::new(0);
+        config.bearer_token = Some("token".to_string());
+        let ready = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let handler = {
+            let ready = ready.clone();
+            MetricsExporter::new(config, Registry::new())
+                .readiness(move || ready.load(std::sync::atomic::Ordering::Relaxed))
+                .handler
+        };
+        let get = |path: &str| {
+            let handler = handler.clone();
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            async move { handler.serve_req(req).await.unwrap().status() }
+        };
+
+        assert_eq!(get("/healthz").await, StatusCode::OK);
+        assert_eq!(get("/readyz").await, StatusCode::SERVICE_UNAVAILABLE);
+        ready.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(get("/readyz").await, StatusCode::OK);
+        assert_eq!(get("/version").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(get("/metrics").await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn serve_compressed_openmetrics() {
+        let registry = Registry::new();
+        let counter = prometheus::IntCounter::new("requests_total", "requests").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+        let handler = MetricsExporter::new(ExporterConfig::new(0), registry).handler;
+        let req = Request::get("/metrics")
+            .header(ACCEPT, "application/openmetrics-text; version=1.0.0")
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let response = handler.serve_req(req).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "Accept, Accept-Encoding");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let mut output = String::new();
+        io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&body[..]), &mut output)
+            .unwrap();
+        assert!(output.contains("requests_total 1.0\n# EOF\n"));
+    }
+
+    #[tokio::test]
+    async fn change_log_level() {
+        struct NopLogger;
+
+        impl log::Log for NopLogger {
+            fn enabled(&self, _metadata: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, _record: &log::Record) {}
+
+            fn flush(&self) {}
+        }
+
+        let mut config = ExporterConfig::new(0);
+        config.log_admin = true;
+        // neither authenticated nor on loopback
+        assert!(
+            !MetricsExporter::new(config.clone(), Registry::new())
+                .handler
+                .log_admin
+        );
+
+        config.bind_addr = "127.0.0.1".parse().unwrap();
+        let handler = MetricsExporter::new(config, Registry::new()).handler;
+        logging::init(NopLogger, LevelFilter::Info).unwrap();
+        let request = |method: Method, uri: &str| {
+            let handler = handler.clone();
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            async move {
+                let response = handler.serve_req(req).await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, body)
+            }
+        };
+
+        let (status, body) = request(
+            Method::PUT,
+            "/loglevel?module=exporter%3A%3Atest&level=debug",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let levels: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(levels["modules"]["exporter::test"], "debug");
+        assert!(log::max_level() >= LevelFilter::Debug);
+
+        let (status, _) = request(Method::PUT, "/loglevel?level=verbose").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) = request(Method::DELETE, "/loglevel?module=exporter::test").await;
+        assert_eq!(status, StatusCode::OK);
+        let levels: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(levels["modules"].get("exporter::test").is_none());
+    }
+
+    #[cfg(feature = "pprof")]
+    #[tokio::test]
+    async fn guard_profile_admin() {
+        let mut config = ExporterConfig::new(0);
+        let request = |handler: Handler| async move {
+            let req = Request::get("/debug/pprof/profile?seconds=0")
+                .body(Body::empty())
+                .unwrap();
+            handler.serve_req(req).await.unwrap().status()
+        };
+
+        let handler = MetricsExporter::new(config.clone(), Registry::new()).handler;
+        assert_eq!(request(handler).await, StatusCode::NOT_FOUND);
+        config.profile_admin = true;
+        // neither authenticated nor on loopback
+        let handler = MetricsExporter::new(config.clone(), Registry::new()).handler;
+        assert_eq!(request(handler).await, StatusCode::NOT_FOUND);
+
+        config.bearer_token = Some("secret".to_string());
+        let handler = MetricsExporter::new(config, Registry::new()).handler;
+        assert_eq!(request(handler.clone()).await, StatusCode::UNAUTHORIZED);
+        let req = Request::get("/debug/pprof/profile?seconds=0")
+            .header(AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = handler.serve_req(req).await.unwrap();
+        // served, and seconds=0 is rejected
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per method concurrency limits for servers.
+//!
+//! [`ConcurrencyLimitLayer`] caps the requests of a method that are handled
+//! at once. Requests over the cap either wait for a running one to return,
+//! or with `shed` set are rejected right away with `RESOURCE_EXHAUSTED` (`8`)
+//! and counted in `grpc_server_rejected_total{service, method}`.
+//!
+//! Limits are keyed like a [`MethodPolicy`](super::MethodPolicy), and methods
+//! without one are not limited:
+//!
+//! ```toml
+//! [controller.concurrency_limits.SendRawTransaction]
+//! max_in_flight = 1000
+//! shed = true
+//! ```
+//!
+//! A request holds its slot until its response body is dropped, so a
+//! streaming response counts against the limit until the stream ends.
+//!
+//! Put the layer inside [`MiddlewareLayer`](super::MiddlewareLayer), so that
+//! rejected requests are also recorded in the server metrics.
+
+use super::{parse_grpc_path, policy};
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::{HeaderMap, Request, Response};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{default_registry, IntCounterVec, Opts, Registry};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic::body::BoxBody;
+use tonic::Status;
+use tower::{Layer, Service};
+
+lazy_static! {
+    static ref REJECTED: RwLock<Option<IntCounterVec>> = RwLock::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ConcurrencyLimit {
+    /// Requests handled at once, so a `0` fails to deserialize.
+    pub max_in_flight: NonZeroUsize,
+    /// Reject requests over the limit instead of making them wait.
+    #[serde(default)]
+    pub shed: bool,
+}
+
+fn register_rejected(registry: &Registry) -> prometheus::Result<IntCounterVec> {
+    let rejected = IntCounterVec::new(
+        Opts::new(
+            "grpc_server_rejected_total",
+            "total number of requests rejected over the concurrency limit",
+        ),
+        &["service", "method"],
+    )?;
+    registry.register(Box::new(rejected.clone()))?;
+    Ok(rejected)
+}
+
+/// Returns the rejection counter of the default registry, registering it on first use.
+fn rejected_counter() -> Option<IntCounterVec> {
+    if let Some(rejected) = REJECTED.read().unwrap().as_ref() {
+        return Some(rejected.clone());
+    }
+
+    let mut write = REJECTED.write().unwrap();
+    if write.is_none() {
+        match register_rejected(default_registry()) {
+            Ok(rejected) => {
+                info!("register grpc rejection metrics succeeded");
+                *write = Some(rejected);
+            }
+            Err(e) => {
+                warn!(
+                    "register grpc rejection metrics failed with error: {}, ignored metrics",
+                    e
+                );
+            }
+        }
+    }
+    write.clone()
+}
+
+#[derive(Debug)]
+struct MethodLimit {
+    shed: bool,
+    semaphore: Arc<Semaphore>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimitLayer {
+    limits: Arc<HashMap<String, MethodLimit>>,
+    rejected: Option<IntCounterVec>,
+}
+
+impl ConcurrencyLimitLayer {
+    pub fn new(limits: HashMap<String, ConcurrencyLimit>) -> Self {
+        ConcurrencyLimitLayer {
+            limits: Arc::new(method_limits(limits)),
+            rejected: rejected_counter(),
+        }
+    }
+
+    /// Registers the rejection counter in `registry` instead of the default one.
+    pub fn with_registry(
+        limits: HashMap<String, ConcurrencyLimit>,
+        registry: &Registry,
+    ) -> prometheus::Result<Self> {
+        Ok(ConcurrencyLimitLayer {
+            limits: Arc::new(method_limits(limits)),
+            rejected: Some(register_rejected(registry)?),
+        })
+    }
+}
+
+fn method_limits(limits: HashMap<String, ConcurrencyLimit>) -> HashMap<String, MethodLimit> {
+    limits
+        .into_iter()
+        .map(|(method, limit)| {
+            let limit = MethodLimit {
+                shed: limit.shed,
+                semaphore: Arc::new(Semaphore::new(limit.max_in_flight.get())),
+            };
+            (method, limit)
+        })
+        .collect()
+}
+
+/// Response body that holds the slot of its request until it is dropped.
+struct PermitBody {
+    inner: BoxBody,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PermitBody {
+    fn wrap(response: Response<BoxBody>, permit: OwnedSemaphorePermit) -> Response<BoxBody> {
+        response.map(|inner| {
+            BoxBody::new(PermitBody {
+                inner,
+                _permit: permit,
+            })
+        })
+    }
+}
+
+impl HttpBody for PermitBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimitService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ConcurrencyLimitService {
+            inner: service,
+            limits: self.limits.clone(),
+            rejected: self.rejected.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimitService<S> {
+    inner: S,
+    limits: Arc<HashMap<String, MethodLimit>>,
+    rejected: Option<IntCounterVec>,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for ConcurrencyLimitService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let limit = parse_grpc_path(req.uri().path()).and_then(|(service, method)| {
+            policy::lookup(&self.limits, service, method).map(|limit| (service, method, limit))
+        });
+        let (service, method, limit) = match limit {
+            Some(limit) => limit,
+            None => {
+                return Box::pin(async move {
+                    let response = inner.call(req).await?;
+                    Ok(response)
+                })
+            }
+        };
+
+        if limit.shed {
+            match limit.semaphore.clone().try_acquire_owned() {
+                Ok(permit) => Box::pin(async move {
+                    let response = inner.call(req).await?;
+                    Ok(PermitBody::wrap(response, permit))
+                }),
+                Err(_) => {
+                    if let Some(rejected) = &self.rejected {
+                        rejected.with_label_values(&[service, method]).inc();
+                    }
+                    let status = Status::resource_exhausted(format!(
+                        "{}/{} is over its concurrency limit",
+                        service, method
+                    ));
+                    Box::pin(async move { Ok(status.to_http()) })
+                }
+            }
+        } else {
+            let semaphore = limit.semaphore.clone();
+            Box::pin(async move {
+                // the semaphore is never closed
+                let permit = semaphore.acquire_owned().await.unwrap();
+                let response = inner.call(req).await?;
+                Ok(PermitBody::wrap(response, permit))
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::grpc_status_code;
+    use hyper::Body;
+
+    #[tokio::test]
+    async fn shed_requests_over_limit() {
+        let (release_tx, release_rx) = tokio::sync::watch::channel(false);
+        let service = tower::service_fn(move |_req: Request<Body>| {
+            let mut release_rx = release_rx.clone();
+            async move {
+                while !*release_rx.borrow_and_update() {
+                    release_rx.changed().await.unwrap();
+                }
+                Ok::<_, Status>(Response::new(BoxBody::default()))
+            }
+        });
+        let registry = Registry::new();
+        let limits = HashMap::from([(
+            "SendRawTransaction".to_string(),
+            ConcurrencyLimit {
+                max_in_flight: NonZeroUsize::new(1).unwrap(),
+                shed: true,
+            },
+        )]);
+        let mut service = ConcurrencyLimitLayer::with_registry(limits, &registry)
+            .unwrap()
+            .layer(service);
+        let request = || {
+            Request::builder()
+                .uri("/controller.RPCService/SendRawTransaction")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let running = tokio::spawn(service.call(request()));
+        let response = service.call(request()).await.unwrap();
+        assert_eq!(grpc_status_code(response.headers()).as_deref(), Some("8"));
+
+        release_tx.send(true).unwrap();
+        let running = running.await.unwrap().unwrap();
+        // the first request is running until its body is dropped
+        let response = service.call(request()).await.unwrap();
+        assert_eq!(grpc_status_code(response.headers()).as_deref(), Some("8"));
+
+        drop(running);
+        let response = service.call(request()).await.unwrap();
+        assert_eq!(grpc_status_code(response.headers()), None);
+
+        let rejected = &registry.gather()[0];
+        assert_eq!(rejected.get_name(), "grpc_server_rejected_total");
+        assert_eq!(rejected.get_metric()[0].get_counter().get_value(), 2.0);
+    }
+
+    #[test]
+    fn reject_zero_limit() {
+        let limits: Result<HashMap<String, ConcurrencyLimit>, _> =
+            toml::from_str("[Exec]\nmax_in_flight = 0\n");
+        assert!(limits.is_err());
+    }
+}
@@ -0,0 +1,968 @@
+//! gRPC server metrics and the prometheus exporter.
+//!
+//! Outgoing calls made by the service helpers are recorded separately, see
+//! [`client`]. Block height, tx pool size and other chain level gauges are
+//! set through [`ChainMetrics`]. To follow a request across services, see
+//! [`trace`].
+//!
+//! Requests handled behind [`MiddlewareLayer`] are recorded in:
+//!
+//! - `grpc_server_handling_ms`: histogram of request latencies in milliseconds
+//! - `grpc_server_handled_total`: counter of completed requests
+//! - `grpc_server_errors_total`: counter of requests completed with a non-`OK` status
+//! - `grpc_server_in_flight`: gauge of requests currently being handled
+//! - `grpc_server_request_bytes`: histogram of request body sizes in bytes
+//! - `grpc_server_response_bytes`: histogram of response body sizes in bytes
+//!
+//! They share these labels (the gauge and the size histograms only have
+//! `service` and `method`):
+//!
+//! - `client`: value of the `client-name` request header, or `unknown`; see
+//!   [`MiddlewareLayer::with_client_labels`] for how many values are kept
+//! - `service`: fully qualified gRPC service, e.g. `crypto.CryptoService`
+//! - `method`: gRPC method, e.g. `HashData`; see
+//!   [`MiddlewareLayer::with_method_labels`] for how many values are kept
+//! - `code`: gRPC status code of the response, e.g. `0` for `OK`
+//!
+//! Clients over their limit are labelled `other` and counted in
+//! `grpc_server_client_label_overflow_total`. Likewise the service and method
+//! of paths over their limit, e.g. of requests to methods that do not exist,
+//! are labelled `other` and counted in
+//! `grpc_server_method_label_overflow_total`. Clients thus cannot grow the
+//! number of series without bound.
+//!
+//! The status code is read from the response headers for trailers-only
+//! responses and from the trailers otherwise, so a request is only recorded
+//! once its response body has been sent. A response dropped before its
+//! trailers is recorded as `1` (`CANCELLED`).
+//!
+//! # Migrating dashboards
+//!
+//! Earlier versions registered one histogram per client and method, named
+//! `{client}_to_{method}`. Those series are no longer exported; queries should
+//! select on labels instead:
+//!
+//! ```text
+//! # before
+//! controller_to_HashData_bucket
+//! # after
+//! grpc_server_handling_ms_bucket{client="controller", method="HashData"}
+//! ```
+//!
+//! Latencies are still measured in milliseconds, so the buckets passed to
+//! [`MiddlewareLayer::new`] keep their meaning.
+//!
+//! # Slow requests and deadlines
+//!
+//! [`MiddlewareLayer::with_policy`] sets a [`MethodPolicy`] per method:
+//! requests slower than its slow threshold are logged with their client and
+//! duration, and requests still running at its deadline are cancelled and
+//! answered with `DEADLINE_EXCEEDED` (`4`). Both cover a request until its
+//! response starts; the body of a streaming response is not bounded by the
+//! deadline nor counted in the duration. How many requests of a method
+//! are handled at once can be capped with a [`ConcurrencyLimitLayer`].
+//!
+//! # Registries
+//!
+//! [`MiddlewareLayer::new`] and [`run_metrics_exporter`] use the prometheus
+//! default registry. Services that embed several servers in one process, or
+//! that want to add their own collectors, can create a [`Registry`] (see
+//! [`node_registry`] for one that labels every metric with the node address
+//! and chain id) and pass it to [`MiddlewareLayer::with_registry`],
+//! [`client::register`], [`ChainMetrics::with_registry`] and
+//! [`run_metrics_exporter_with_registry`].
+//!
+//! How the exporter listens (address, TLS, authentication) is set by an
+//! [`ExporterConfig`] passed to [`run_metrics_exporter_with_config`]. Besides
+//! `/metrics`, the exporter serves `/healthz`, `/readyz` and `/version` for
+//! probes, see [`MetricsExporter`]. Scrapes are answered in the prometheus
+//! text, OpenMetrics or protobuf format depending on their `Accept` header,
+//! and gzip compressed when allowed, see [`encoding`].
+//!
+//! Nodes that cannot be scraped can push their metrics to a Pushgateway
+//! instead, see [`run_metrics_pusher`].
+//!
+//! With the `process-metrics` feature, the exporter also serves process and
+//! tokio runtime metrics, see `register_process_metrics`. With the `pprof`
+//! feature, it serves CPU profiles on `/debug/pprof/profile`.
+
+mod cardinality;
+mod chain;
+pub mod client;
+pub mod encoding;
+mod exporter;
+mod limit;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+mod policy;
+#[cfg(feature = "process-metrics")]
+mod process;
+#[cfg(feature = "pprof")]
+mod profile;
+mod push;
+pub mod trace;
+
+pub use cardinality::{
+    LabelLimit, DEFAULT_CLIENT_LABEL_CAP, DEFAULT_METHOD_LABEL_CAP, OTHER_LABEL,
+};
+pub use chain::ChainMetrics;
+pub use client::observe_client_call;
+pub use exporter::{
+    run_metrics_exporter, run_metrics_exporter_with_config, run_metrics_exporter_with_registry,
+    run_metrics_exporter_with_shutdown, BasicAuthConfig, BuildInfo, ExporterConfig,
+    MetricsExporter, ReadinessCheck, TlsConfig,
+};
+pub use limit::{ConcurrencyLimit, ConcurrencyLimitLayer, ConcurrencyLimitService};
+pub use policy::MethodPolicy;
+#[cfg(feature = "process-metrics")]
+pub use process::register_process_metrics;
+pub use push::{run_metrics_pusher, PushConfig};
+pub use trace::{TraceContext, TraceInterceptor, TraceLayer, TracedClient};
+
+use cardinality::LabelGuard;
+use hyper::{
+    body::{Bytes, HttpBody, SizeHint},
+    Body, HeaderMap, Request, Response,
+};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{
+    core::Collector, default_registry, exponential_buckets, Histogram, HistogramOpts, HistogramVec,
+    IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::time::Instant;
+use std::{
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+};
+use tonic::body::BoxBody;
+use tonic::Status;
+use tower::{Layer, Service};
+
+const CLIENT_NAME_HEADER: &str = "client-name";
+const GRPC_STATUS_HEADER: &str = "grpc-status";
+const UNKNOWN_LABEL: &str = "unknown";
+const CODE_OK: &str = "0";
+const CODE_CANCELLED: &str = "1";
+
+lazy_static! {
+    static ref SERVER_METRICS: RwLock<Option<ServerMetrics>> = RwLock::new(None);
+}
+
+#[derive(Debug, Clone)]
+struct ServerMetrics {
+    latency: HistogramVec,
+    handled: IntCounterVec,
+    errors: IntCounterVec,
+    in_flight: IntGaugeVec,
+    request_bytes: HistogramVec,
+    response_bytes: HistogramVec,
+    client_overflow: IntCounter,
+    method_overflow: IntCounter,
+}
+
+impl ServerMetrics {
+    fn register(buckets: Vec<f64>, registry: &Registry) -> prometheus::Result<Self> {
+        let labels = &["client", "service", "method", "code"];
+        // 64B to 64MB
+        let size_buckets = exponential_buckets(64.0, 4.0, 11)?;
+        let metrics = ServerMetrics {
+            latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "grpc_server_handling_ms",
+                    "request latencies in milliseconds(ms)",
+                )
+                .buckets(buckets),
+                labels,
+            )?,
+            handled: IntCounterVec::new(
+                Opts::new(
+                    "grpc_server_handled_total",
+                    "total number of completed requests",
+                ),
+                labels,
+            )?,
+            errors: IntCounterVec::new(
+                Opts::new(
+                    "grpc_server_errors_total",
+                    "total number of requests completed with a non-OK status",
+                ),
+                labels,
+            )?,
+            in_flight: IntGaugeVec::new(
+                Opts::new(
+                    "grpc_server_in_flight",
+                    "number of requests currently being handled",
+                ),
+                &["service", "method"],
+            )?,
+            request_bytes: HistogramVec::new(
+                HistogramOpts::new("grpc_server_request_bytes", "request body sizes in bytes")
+                    .buckets(size_buckets.clone()),
+                &["service", "method"],
+            )?,
+            response_bytes: HistogramVec::new(
+                HistogramOpts::new("grpc_server_response_bytes", "response body sizes in bytes")
+                    .buckets(size_buckets),
+                &["service", "method"],
+            )?,
+            client_overflow: IntCounter::new(
+                "grpc_server_client_label_overflow_total",
+                "total number of requests whose client was labelled `other`",
+            )?,
+            method_overflow: IntCounter::new(
+                "grpc_server_method_label_overflow_total",
+                "total number of requests whose service and method were labelled `other`",
+            )?,
+        };
+
+        register_all(
+            registry,
+            vec![
+                twice(&metrics.latency),
+                twice(&metrics.handled),
+                twice(&metrics.errors),
+                twice(&metrics.in_flight),
+                twice(&metrics.request_bytes),
+                twice(&metrics.response_bytes),
+                twice(&metrics.client_overflow),
+                twice(&metrics.method_overflow),
+            ],
+        )?;
+        Ok(metrics)
+    }
+}
+
+/// Registers all of `collectors` or, if one fails, none of them, so that a
+/// failed registration can be tried again.
+fn register_all(
+    registry: &Registry,
+    collectors: Vec<(Box<dyn Collector>, Box<dyn Collector>)>,
+) -> prometheus::Result<()> {
+    let mut registered = Vec::with_capacity(collectors.len());
+    for (collector, copy) in collectors {
+        if let Err(e) = registry.register(collector) {
+            for copy in registered {
+                let _ = registry.unregister(copy);
+            }
+            return Err(e);
+        }
+        registered.push(copy);
+    }
+    Ok(())
+}
+
+/// A collector to register and a copy to unregister it with.
+fn twice<C: Collector + Clone + 'static>(
+    collector: &C,
+) -> (Box<dyn Collector>, Box<dyn Collector>) {
+    (Box::new(collector.clone()), Box::new(collector.clone()))
+}
+
+/// Returns the server metrics of the default registry, registering them with
+/// `buckets` on first use.
+///
+/// The metrics can only be registered once per registry, so the buckets of
+/// later layers are ignored.
+fn server_metrics(buckets: Vec<f64>) -> Option<ServerMetrics> {
+    if let Some(metrics) = SERVER_METRICS.read().unwrap().as_ref() {
+        return Some(metrics.clone());
+    }
+
+    let mut write = SERVER_METRICS.write().unwrap();
+    if write.is_none() {
+        match ServerMetrics::register(buckets, default_registry()) {
+            Ok(metrics) => {
+                info!("register grpc server metrics succeeded");
+                *write = Some(metrics);
+            }
+            Err(e) => {
+                warn!(
+                    "register grpc server metrics failed with error: {}, ignored metrics",
+                    e
+                );
+            }
+        }
+    }
+    write.clone()
+}
+
+/// Creates a registry that adds `node_address` and `chain_id` labels to every metric.
+pub fn node_registry(node_address: &str, chain_id: &str) -> prometheus::Result<Registry> {
+    let labels = HashMap::from([
+        ("node_address".to_string(), node_address.to_string()),
+        ("chain_id".to_string(), chain_id.to_string()),
+    ]);
+    Registry::new_custom(None, Some(labels))
+}
+
+/// Splits a gRPC request path `/package.Service/Method` into service and method.
+fn parse_grpc_path(path: &str) -> Option<(&str, &str)> {
+    let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+    if service.is_empty() || method.is_empty() {
+        None
+    } else {
+        Some((service, method))
+    }
+}
+
+fn grpc_status_code(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(GRPC_STATUS_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Observes the number of bytes that went through a body when dropped.
+struct BodySize {
+    histogram: Histogram,
+    bytes: usize,
+}
+
+impl BodySize {
+    fn add(&mut self, bytes: usize) {
+        self.bytes += bytes;
+    }
+}
+
+impl Drop for BodySize {
+    fn drop(&mut self) {
+        self.histogram.observe(self.bytes as f64);
+    }
+}
+
+/// Request body data, counted as the inner service reads it.
+struct CountedData {
+    body: Body,
+    size: BodySize,
+}
+
+impl futures::Stream for CountedData {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let data = Pin::new(&mut this.body).poll_data(cx);
+        if let Poll::Ready(Some(Ok(ref data))) = data {
+            this.size.add(data.len());
+        }
+        data
+    }
+}
+
+/// Records the size of a request body once it has been consumed.
+///
+/// A body of known length is passed on untouched and its length recorded.
+/// Any other body, which had no size hint to lose, is counted as it is read
+/// in the task of the request. Its trailers are not passed on, since a
+/// `hyper::Body`, which tonic servers take, cannot wrap another body with
+/// them; gRPC clients do not send request trailers.
+fn metered_request_body(body: Body, histogram: Histogram) -> Body {
+    if let Some(len) = body.size_hint().exact() {
+        histogram.observe(len as f64);
+        return body;
+    }
+
+    Body::wrap_stream(CountedData {
+        body,
+        size: BodySize {
+            histogram,
+            bytes: 0,
+        },
+    })
+}
+
+/// Records one request when its response completes.
+struct RequestRecorder {
+    metrics: ServerMetrics,
+    client: String,
+    service: String,
+    method: String,
+    code: Option<String>,
+    response_bytes: usize,
+    started: Instant,
+}
+
+impl RequestRecorder {
+    fn new(metrics: ServerMetrics, client: String, service: String, method: String) -> Self {
+        metrics
+            .in_flight
+            .with_label_values(&[&service, &method])
+            .inc();
+        RequestRecorder {
+            metrics,
+            client,
+            service,
+            method,
+            code: None,
+            response_bytes: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for RequestRecorder {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed().as_secs_f64() * 1000f64;
+        let code = self.code.as_deref().unwrap_or(CODE_CANCELLED);
+        let labels = [
+            self.client.as_str(),
+            self.service.as_str(),
+            self.method.as_str(),
+            code,
+        ];
+
+        self.metrics
+            .in_flight
+            .with_label_values(&[&self.service, &self.method])
+            .dec();
+        self.metrics
+            .response_bytes
+            .with_label_values(&[&self.service, &self.method])
+            .observe(self.response_bytes as f64);
+        self.metrics
+            .latency
+            .with_label_values(&labels)
+            .observe(elapsed);
+        self.metrics.handled.with_label_values(&labels).inc();
+        if code != CODE_OK {
+            self.metrics.errors.with_label_values(&labels).inc();
+        }
+    }
+}
+
+/// Response body that hands the gRPC status and its size to its [`RequestRecorder`].
+///
+/// The request is recorded once the body is dropped, which hyper does after
+/// the trailers have been sent or the stream has been reset.
+struct MeteredBody {
+    inner: BoxBody,
+    recorder: RequestRecorder,
+}
+
+impl HttpBody for MeteredBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        let data = Pin::new(&mut this.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(ref data))) = data {
+            this.recorder.response_bytes += data.len();
+        }
+        data
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = self.get_mut();
+        let trailers = Pin::new(&mut this.inner).poll_trailers(cx);
+        if let Poll::Ready(Ok(Some(ref trailers))) = trailers {
+            if let Some(code) = grpc_status_code(trailers) {
+                this.recorder.code = Some(code);
+            }
+        }
+        trailers
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MiddlewareLayer {
+    metrics: Option<ServerMetrics>,
+    policies: Arc<HashMap<String, MethodPolicy>>,
+    client_labels: Arc<LabelGuard>,
+    method_labels: Arc<LabelGuard>,
+}
+
+impl MiddlewareLayer {
+    pub fn new(buckets: Vec<f64>) -> Self {
+        MiddlewareLayer {
+            metrics: server_metrics(buckets),
+            policies: Arc::new(HashMap::new()),
+            client_labels: Arc::new(LabelGuard::new(LabelLimit::Cap(DEFAULT_CLIENT_LABEL_CAP))),
+            method_labels: Arc::new(LabelGuard::new(LabelLimit::Cap(DEFAULT_METHOD_LABEL_CAP))),
+        }
+    }
+
+    /// Registers the server metrics in `registry` instead of the default one.
+    ///
+    /// Fails if they are already registered there; clone the returned layer
+    /// to use it for several servers sharing a registry.
+    pub fn with_registry(buckets: Vec<f64>, registry: &Registry) -> prometheus::Result<Self> {
+        Ok(MiddlewareLayer {
+            metrics: Some(ServerMetrics::register(buckets, registry)?),
+            policies: Arc::new(HashMap::new()),
+            client_labels: Arc::new(LabelGuard::new(LabelLimit::Cap(DEFAULT_CLIENT_LABEL_CAP))),
+            method_labels: Arc::new(LabelGuard::new(LabelLimit::Cap(DEFAULT_METHOD_LABEL_CAP))),
+        })
+    }
+
+    /// Sets the policy of `method`, either `Service/Method` or `Method`, see
+    /// [`MethodPolicy`].
+    pub fn with_policy(mut self, method: &str, policy: MethodPolicy) -> Self {
+        Arc::make_mut(&mut self.policies).insert(method.to_string(), policy);
+        self
+    }
+
+    /// Sets the policies of several methods, e.g. read from the service config.
+    pub fn with_policies(mut self, policies: HashMap<String, MethodPolicy>) -> Self {
+        Arc::make_mut(&mut self.policies).extend(policies);
+        self
+    }
+
+    /// Sets which clients get their own `client` label, see [`LabelLimit`].
+    pub fn with_client_labels(mut self, limit: LabelLimit) -> Self {
+        self.client_labels = Arc::new(LabelGuard::new(limit));
+        self
+    }
+
+    /// Sets which methods, as `Service/Method`, get their own `service` and
+    /// `method` labels, see [`LabelLimit`].
+    pub fn with_method_labels(mut self, limit: LabelLimit) -> Self {
+        self.method_labels = Arc::new(LabelGuard::new(limit));
+        self
+    }
+}
+
+impl<S> Layer<S> for MiddlewareLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        MetricsService {
+            inner: service,
+            metrics: self.metrics.clone(),
+            policies: self.policies.clone(),
+            client_labels: self.client_labels.clone(),
+            method_labels: self.method_labels.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Option<ServerMetrics>,
+    policies: Arc<HashMap<String, MethodPolicy>>,
+    client_labels: Arc<LabelGuard>,
+    method_labels: Arc<LabelGuard>,
+}
+
+impl<S> Service<Request<Body>> for MetricsService<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let (service, method) = match parse_grpc_path(req.uri().path()) {
+            Some((service, method)) => (service.to_string(), method.to_string()),
+            None => {
+                return Box::pin(async move {
+                    let response = inner.call(req).await?;
+                    Ok(response)
+                })
+            }
+        };
+        let client_name = req
+            .headers()
+            .get(CLIENT_NAME_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or(UNKNOWN_LABEL)
+            .to_string();
+        let policy = policy::method_policy(&self.policies, &service, &method);
+        let metrics = self.metrics.clone();
+        let client_labels = self.client_labels.clone();
+        let method_labels = self.method_labels.clone();
+
+        Box::pin(async move {
+            let path = format!("{}/{}", service, method);
+            let (req, recorder) = match metrics {
+                Some(metrics) => {
+                    let (service, method) = if method_labels.allows(&path) {
+                        (service, method)
+                    } else {
+                        metrics.method_overflow.inc();
+                        (OTHER_LABEL.to_string(), OTHER_LABEL.to_string())
+                    };
+                    let request_bytes = metrics
+                        .request_bytes
+                        .with_label_values(&[&service, &method]);
+                    let req = req.map(|body| metered_request_body(body, request_bytes));
+                    let client_label = if client_labels.allows(&client_name) {
+                        client_name.clone()
+                    } else {
+                        metrics.client_overflow.inc();
+                        OTHER_LABEL.to_string()
+                    };
+                    let recorder = RequestRecorder::new(metrics, client_label, service, method);
+                    (req, Some(recorder))
+                }
+                None => (req, None),
+            };
+
+            let started = Instant::now();
+            let mut timed_out = false;
+            let response = match policy.deadline() {
+                Some(deadline) => match tokio::time::timeout(deadline, inner.call(req)).await {
+                    Ok(response) => response?,
+                    Err(_) => {
+                        timed_out = true;
+                        warn!(
+                            "request {} from {} cancelled after its deadline of {}ms",
+                            path,
+                            client_name,
+                            deadline.as_millis()
+                        );
+                        Status::deadline_exceeded(format!(
+                            "{} exceeded its deadline of {}ms",
+                            path,
+                            deadline.as_millis()
+                        ))
+                        .to_http()
+                    }
+                },
+                None => inner.call(req).await?,
+            };
+            let elapsed = started.elapsed();
+            // a request cancelled at its deadline has been logged already
+            match policy.slow_threshold() {
+                Some(threshold) if elapsed >= threshold && !timed_out => warn!(
+                    "slow request {} from {} took {}ms",
+                    path,
+                    client_name,
+                    elapsed.as_millis()
+                ),
+                _ => {}
+            }
+
+            Ok(match recorder {
+                Some(mut recorder) => {
+                    recorder.code = grpc_status_code(response.headers());
+                    response.map(|body| {
+                        BoxBody::new(MeteredBody {
+                            inner: body,
+                            recorder,
+                        })
+                    })
+                }
+                None => response,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registries_do_not_collide() {
+        let first = node_registry("0x01", "0xaa").unwrap();
+        let second = node_registry("0x02", "0xaa").unwrap();
+        assert!(MiddlewareLayer::with_registry(vec![1.0, 10.0], &first).is_ok());
+        assert!(MiddlewareLayer::with_registry(vec![1.0, 10.0], &second).is_ok());
+        assert!(MiddlewareLayer::with_registry(vec![1.0, 10.0], &first).is_err());
+
+        let in_flight =
+            IntGaugeVec::new(Opts::new("in_flight", "test gauge"), &["service"]).unwrap();
+        first.register(Box::new(in_flight.clone())).unwrap();
+        in_flight.with_label_values(&["crypto"]).inc();
+        let families = first.gather();
+        let in_flight = families
+            .iter()
+            .find(|mf| mf.get_name() == "in_flight")
+            .unwrap();
+        let labels = in_flight.get_metric()[0].get_label();
+        assert!(labels
+            .iter()
+            .any(|l| l.get_name() == "node_address" && l.get_value() == "0x01"));
+        assert!(labels
+            .iter()
+            .any(|l| l.get_name() == "chain_id" && l.get_value() == "0xaa"));
+    }
+
+    #[test]
+    fn register_all_or_nothing() {
+        let registry = Registry::new();
+        // same as the server's, since a name keeps its labels once registered
+        let taken = IntGaugeVec::new(
+            Opts::new(
+                "grpc_server_in_flight",
+                "number of requests currently being handled",
+            ),
+            &["service", "method"],
+        )
+        .unwrap();
+        registry.register(Box::new(taken.clone())).unwrap();
+        assert!(MiddlewareLayer::with_registry(vec![1.0, 10.0], &registry).is_err());
+
+        registry.unregister(Box::new(taken)).unwrap();
+        assert!(MiddlewareLayer::with_registry(vec![1.0, 10.0], &registry).is_ok());
+    }
+
+    fn label<'a>(metric: &'a prometheus::proto::Metric, name: &str) -> &'a str {
+        metric
+            .get_label()
+            .iter()
+            .find(|l| l.get_name() == name)
+            .map(|l| l.get_value())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn label_latency_histogram() {
+        let service = tower::service_fn(|_req: Request<Body>| async move {
+            Ok::<_, Status>(Response::new(BoxBody::default()))
+        });
+        let registry = Registry::new();
+        let mut service = MiddlewareLayer::with_registry(vec![1.0, 10.0], &registry)
+            .unwrap()
+            .layer(service);
+        let req = Request::builder()
+            .uri("/crypto.CryptoService/HashData")
+            .header(CLIENT_NAME_HEADER, "controller")
+            .body(Body::empty())
+            .unwrap();
+        let response = service.call(req).await.unwrap();
+        drop(response);
+
+        let latency = registry
+            .gather()
+            .into_iter()
+            .find(|mf| mf.get_name() == "grpc_server_handling_ms")
+            .unwrap();
+        let metric = &latency.get_metric()[0];
+        assert_eq!(label(metric, "client"), "controller");
+        assert_eq!(label(metric, "service"), "crypto.CryptoService");
+        assert_eq!(label(metric, "method"), "HashData");
+        assert_eq!(label(metric, "code"), CODE_CANCELLED);
+        let buckets: Vec<f64> = metric
+            .get_histogram()
+            .get_bucket()
+            .iter()
+            .map(|b| b.get_upper_bound())
+            .collect();
+        assert_eq!(buckets, [1.0, 10.0]);
+        assert_eq!(metric.get_histogram().get_sample_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn fold_unknown_methods() {
+        let service = tower::service_fn(|_req: Request<Body>| async move {
+            Ok::<_, Status>(Status::unimplemented("").to_http())
+        });
+        let registry = Registry::new();
+        let mut service = MiddlewareLayer::with_registry(vec![1.0, 10.0], &registry)
+            .unwrap()
+            .with_method_labels(LabelLimit::Allowlist(vec![
+                "crypto.CryptoService/HashData".to_string()
+            ]))
+            .layer(service);
+        for path in ["/crypto.CryptoService/HashData", "/a/b", "/c/d"] {
+            let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+            service.call(req).await.unwrap();
+        }
+
+        let families = registry.gather();
+        let handled = families
+            .iter()
+            .find(|mf| mf.get_name() == "grpc_server_handled_total")
+            .unwrap();
+        let mut methods: Vec<(&str, u64)> = handled
+            .get_metric()
+            .iter()
+            .map(|m| (label(m, "method"), m.get_counter().get_value() as u64))
+            .collect();
+        methods.sort();
+        assert_eq!(methods, [("HashData", 1), (OTHER_LABEL, 2)]);
+        let overflow = families
+            .iter()
+            .find(|mf| mf.get_name() == "grpc_server_method_label_overflow_total")
+            .unwrap();
+        assert_eq!(overflow.get_metric()[0].get_counter().get_value(), 2.0);
+    }
+
+    #[tokio::test]
+    async fn read_status_from_headers_or_trailers() {
+        let service = tower::service_fn(|req: Request<Body>| async move {
+            let response = match req.uri().path() {
+                // trailers-only error
+                "/test.Service/Unavailable" => Status::unavailable("down").to_http(),
+                _ => {
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        sender.send_data(Bytes::from_static(b"data")).await.unwrap();
+                        let mut trailers = HeaderMap::new();
+                        trailers.insert(GRPC_STATUS_HEADER, "5".parse().unwrap());
+                        sender.send_trailers(trailers).await.unwrap();
+                    });
+                    Response::new(
+                        body.map_err(|e| Status::internal(e.to_string()))
+                            .boxed_unsync(),
+                    )
+                }
+            };
+            Ok::<_, Status>(response)
+        });
+        let registry = Registry::new();
+        let mut service = MiddlewareLayer::with_registry(vec![1.0, 10.0], &registry)
+            .unwrap()
+            .layer(service);
+        let mut call = |method: &str| {
+            let req = Request::builder()
+                .uri(format!("/test.Service/{}", method))
+                .body(Body::empty())
+                .unwrap();
+            service.call(req)
+        };
+
+        let response = call("Unavailable").await.unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let mut body = call("NotFound").await.unwrap().into_body();
+        while body.data().await.is_some() {}
+        assert!(body.trailers().await.unwrap().is_some());
+        drop(body);
+
+        // dropped before its trailers
+        let mut body = call("Dropped").await.unwrap().into_body();
+        body.data().await.unwrap().unwrap();
+        drop(body);
+
+        let families = registry.gather();
+        let handled = families
+            .iter()
+            .find(|mf| mf.get_name() == "grpc_server_handled_total")
+            .unwrap();
+        let mut codes: Vec<(&str, &str)> = handled
+            .get_metric()
+            .iter()
+            .map(|m| (label(m, "method"), label(m, "code")))
+            .collect();
+        codes.sort();
+        assert_eq!(
+            codes,
+            [("Dropped", "1"), ("NotFound", "5"), ("Unavailable", "14")]
+        );
+        let errors = families
+            .iter()
+            .find(|mf| mf.get_name() == "grpc_server_errors_total")
+            .unwrap();
+        assert_eq!(errors.get_metric().len(), 3);
+        let in_flight = families
+            .iter()
+            .find(|mf| mf.get_name() == "grpc_server_in_flight")
+            .unwrap();
+        assert!(in_flight
+            .get_metric()
+            .iter()
+            .all(|m| m.get_gauge().get_value() == 0.0));
+    }
+
+    #[tokio::test]
+    async fn record_body_sizes() {
+        // echoes the request body
+        let service = tower::service_fn(|req: Request<Body>| async move {
+            let data = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let body = Body::from(data)
+                .map_err(|e| Status::internal(e.to_string()))
+                .boxed_unsync();
+            Ok::<_, Status>(Response::new(body))
+        });
+        let registry = Registry::new();
+        let mut service = MiddlewareLayer::with_registry(vec![1.0, 10.0], &registry)
+            .unwrap()
+            .layer(service);
+
+        let (mut sender, streamed) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from_static(b"abc")).await.unwrap();
+            sender.send_data(Bytes::from_static(b"de")).await.unwrap();
+        });
+        for (method, body) in [
+            ("Streamed", streamed),
+            ("Sized", Body::from("0123456789ab")),
+        ] {
+            let req = Request::builder()
+                .uri(format!("/test.Service/{}", method))
+                .body(body)
+                .unwrap();
+            let response = service.call(req).await.unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+
+        let families = registry.gather();
+        for name in ["grpc_server_request_bytes", "grpc_server_response_bytes"] {
+            let family = families.iter().find(|mf| mf.get_name() == name).unwrap();
+            let mut sizes: Vec<(&str, f64)> = family
+                .get_metric()
+                .iter()
+                .map(|m| (label(m, "method"), m.get_histogram().get_sample_sum()))
+                .collect();
+            sizes.sort_by(|a, b| a.0.cmp(b.0));
+            assert_eq!(sizes, [("Sized", 12.0), ("Streamed", 5.0)], "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_requests_after_deadline() {
+        let service = tower::service_fn(|req: Request<Body>| async move {
+            if req.uri().path().ends_with("/Exec") {
+                tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            }
+            Ok::<_, Status>(Response::new(BoxBody::default()))
+        });
+        let policy = MethodPolicy {
+            slow_threshold_ms: None,
+            deadline_ms: Some(50),
+        };
+        let registry = Registry::new();
+        let mut service = MiddlewareLayer::with_registry(vec![1.0, 10.0], &registry)
+            .unwrap()
+            .with_policy("Exec", policy)
+            .layer(service);
+
+        for (method, code) in [("Exec", Some("4")), ("Call", None)] {
+            let req = Request::builder()
+                .uri(format!("/executor.ExecutorService/{}", method))
+                .body(Body::empty())
+                .unwrap();
+            let response = service.call(req).await.unwrap();
+            assert_eq!(grpc_status_code(response.headers()).as_deref(), code);
+        }
+        let errors = registry
+            .gather()
+            .into_iter()
+            .find(|mf| mf.get_name() == "grpc_server_errors_total")
+            .unwrap();
+        assert!(errors.get_metric().iter().any(|m| m
+            .get_label()
+            .iter()
+            .any(|l| l.get_name() == "code" && l.get_value() == "4")));
+    }
+}
@@ -0,0 +1,403 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exports the spans of [`trace`](super::trace) to an OpenTelemetry collector,
+//! enabled by the `otlp` feature.
+//!
+//! Spans are sent in batches with OTLP/HTTP in its JSON encoding, which every
+//! collector accepts on its `/v1/traces` endpoint (port 4318 by default).
+//! Only spans that carry a `trace_id` and a `span_id` field are exported,
+//! unless their `sampled` field is false.
+//!
+//! ```toml
+//! [controller.otlp]
+//! endpoint = "http://otel-collector:4318"
+//! service_name = "controller"
+//! ```
+//!
+//! ```ignore
+//! let (layer, exporter) = otlp_layer(config);
+//! tracing_subscriber::registry().with(layer).init();
+//! tokio::spawn(exporter.run(shutdown_signal()));
+//! ```
+
+use futures::Future;
+use hyper::{header::CONTENT_TYPE, Body, Client, Method, Request};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Spans waiting to be exported; more are dropped until the next batch is sent.
+const QUEUE_SIZE: usize = 4096;
+/// OTLP `SPAN_KIND_SERVER`.
+const SPAN_KIND_SERVER: u32 = 2;
+/// OTLP `STATUS_CODE_ERROR`.
+const STATUS_CODE_ERROR: u32 = 2;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OtlpConfig {
+    /// Base url of the collector, only `http` is supported.
+    pub endpoint: String,
+    /// `service.name` of the exported spans.
+    pub service_name: String,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Longest time a span waits for its batch to fill.
+    #[serde(default = "default_flush_interval_secs")]
+    pub flush_interval_secs: u64,
+}
+
+fn default_batch_size() -> usize {
+    512
+}
+
+fn default_flush_interval_secs() -> u64 {
+    5
+}
+
+/// A finished span, as sent to the collector.
+#[derive(Debug)]
+struct SpanData {
+    name: &'static str,
+    trace_id: String,
+    span_id: String,
+    parent_span_id: String,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl SpanData {
+    fn to_json(&self) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+            .collect();
+        let failed = self
+            .attributes
+            .iter()
+            .any(|(key, value)| *key == "rpc.grpc.status_code" && value != "0");
+        let status = if failed {
+            json!({ "code": STATUS_CODE_ERROR })
+        } else {
+            json!({})
+        };
+        json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "parentSpanId": self.parent_span_id,
+            "name": self.name,
+            "kind": SPAN_KIND_SERVER,
+            "startTimeUnixNano": unix_nanos(self.start).to_string(),
+            "endTimeUnixNano": unix_nanos(self.end).to_string(),
+            "attributes": attributes,
+            "status": status,
+        })
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+/// Collects the fields of a span, keeping the trace ids apart.
+#[derive(Default)]
+struct FieldVisitor {
+    trace_id: Option<String>,
+    span_id: Option<String>,
+    parent_span_id: Option<String>,
+    sampled: Option<bool>,
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "sampled" {
+            self.sampled = Some(value);
+        } else {
+            self.record(field, value.to_string());
+        }
+    }
+}
+
+impl FieldVisitor {
+    fn record(&mut self, field: &Field, value: String) {
+        match field.name() {
+            "trace_id" => self.trace_id = Some(value),
+            "span_id" => self.span_id = Some(value),
+            "parent_span_id" => self.parent_span_id = Some(value),
+            name => self.attributes.push((name, value)),
+        }
+    }
+}
+
+/// A `tracing` layer that queues the traced spans for an [`OtlpExporter`].
+pub struct OtlpLayer {
+    sender: mpsc::Sender<SpanData>,
+}
+
+/// Sends the spans queued by its [`OtlpLayer`] to the collector.
+pub struct OtlpExporter {
+    config: OtlpConfig,
+    receiver: mpsc::Receiver<SpanData>,
+}
+
+pub fn otlp_layer(config: OtlpConfig) -> (OtlpLayer, OtlpExporter) {
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    (OtlpLayer { sender }, OtlpExporter { config, receiver })
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        let traced = visitor.trace_id.is_some() && visitor.span_id.is_some();
+        if traced && visitor.sampled != Some(false) {
+            if let Some(span) = ctx.span(id) {
+                span.extensions_mut().insert((visitor, SystemTime::now()));
+            }
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some((visitor, _)) = span
+                .extensions_mut()
+                .get_mut::<(FieldVisitor, SystemTime)>()
+            {
+                values.record(visitor);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let (visitor, start) = match span.extensions_mut().remove::<(FieldVisitor, SystemTime)>() {
+            Some(fields) => fields,
+            None => return,
+        };
+        let data = SpanData {
+            name: span.name(),
+            trace_id: visitor.trace_id.unwrap_or_default(),
+            span_id: visitor.span_id.unwrap_or_default(),
+            parent_span_id: visitor.parent_span_id.unwrap_or_default(),
+            start,
+            end: SystemTime::now(),
+            attributes: visitor.attributes,
+        };
+        // never block the traced task, the exporter is behind
+        let _ = self.sender.try_send(data);
+    }
+}
+
+impl OtlpExporter {
+    /// Exports batches of spans until `shutdown` completes, then exports the
+    /// spans still queued.
+    pub async fn run(mut self, shutdown: impl Future<Output = ()>) {
+        let client = Client::new();
+        let url = format!("{}/v1/traces", self.config.endpoint.trim_end_matches('/'));
+        let batch_size = self.config.batch_size.max(1);
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.flush_interval_secs.max(1)));
+        let mut batch = Vec::with_capacity(batch_size);
+        info!("exporting spans to {}", url);
+
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                span = self.receiver.recv() => match span {
+                    Some(span) => {
+                        batch.push(span);
+                        if batch.len() >= batch_size {
+                            self.export(&client, &url, &mut batch).await;
+                        }
+                    }
+                    None => break,
+                },
+                _ = interval.tick() => self.export(&client, &url, &mut batch).await,
+                _ = &mut shutdown => break,
+            }
+        }
+        while let Ok(span) = self.receiver.try_recv() {
+            batch.push(span);
+        }
+        self.export(&client, &url, &mut batch).await;
+        info!("span exporter to {} stopped", url);
+    }
+
+    async fn export(
+        &self,
+        client: &Client<hyper::client::HttpConnector>,
+        url: &str,
+        batch: &mut Vec<SpanData>,
+    ) {
+        if batch.is_empty() {
+            return;
+        }
+        let spans: Vec<Value> = batch.drain(..).map(|span| span.to_json()).collect();
+        let count = spans.len();
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": {"stringValue": self.config.service_name},
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+                    "spans": spans,
+                }],
+            }],
+        });
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()));
+        let result = match req {
+            Ok(req) => client.request(req).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => warn!(
+                "export {} spans to {} failed: collector returned {}",
+                count,
+                url,
+                response.status()
+            ),
+            Err(e) => warn!("export {} spans to {} failed: {}", count, url, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::trace::{TraceContext, TraceLayer, TRACEPARENT_HEADER};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use std::convert::Infallible;
+    use tower::{Layer, Service};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tokio::test]
+    async fn export_to_stub_collector() {
+        let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+        let make_svc = make_service_fn(move |_conn| {
+            let received_tx = received_tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let received_tx = received_tx.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        received_tx.send((path, body)).unwrap();
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let (layer, exporter) = otlp_layer(OtlpConfig {
+            endpoint: format!("http://{}", addr),
+            service_name: "crypto".to_string(),
+            batch_size: 512,
+            flush_interval_secs: 60,
+        });
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let parent = TraceContext::new_root();
+        {
+            let _guard = tracing::subscriber::set_default(subscriber);
+            let mut service = TraceLayer.layer(tower::service_fn(|_req: Request<Body>| async {
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .header("grpc-status", "14")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            }));
+            let req = Request::builder()
+                .uri("/crypto.CryptoService/HashData")
+                .header(TRACEPARENT_HEADER, parent.traceparent())
+                .body(Body::empty())
+                .unwrap();
+            service.call(req).await.unwrap();
+            // not sampled, so not exported
+            let unsampled = TraceContext {
+                sampled: false,
+                ..TraceContext::new_root()
+            };
+            let req = Request::builder()
+                .uri("/crypto.CryptoService/HashData")
+                .header(TRACEPARENT_HEADER, unsampled.traceparent())
+                .body(Body::empty())
+                .unwrap();
+            service.call(req).await.unwrap();
+            // not traced, so not exported
+            tracing::info_span!("untraced").in_scope(|| {});
+        }
+        exporter.run(async {}).await;
+
+        let (path, body) = received_rx.recv().await.unwrap();
+        assert_eq!(path, "/v1/traces");
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let resource_spans = &body["resourceSpans"][0];
+        assert_eq!(
+            resource_spans["resource"]["attributes"][0]["value"]["stringValue"],
+            "crypto"
+        );
+        let spans = resource_spans["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0]["name"], "grpc.server");
+        assert_eq!(spans[0]["traceId"], parent.trace_id_hex().as_str());
+        assert_eq!(spans[0]["parentSpanId"], parent.span_id_hex().as_str());
+        assert_eq!(spans[0]["status"]["code"], STATUS_CODE_ERROR);
+        assert!(spans[0]["attributes"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({"key": "rpc.method", "value": {"stringValue": "HashData"}})));
+    }
+}
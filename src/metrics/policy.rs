@@ -0,0 +1,111 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per method settings of [`MiddlewareLayer`](super::MiddlewareLayer).
+//!
+//! Policies are keyed by `Service/Method`, e.g. `executor.ExecutorService/Call`,
+//! or by the method alone, e.g. `Exec`; the full key wins when both match.
+//!
+//! ```toml
+//! [controller.method_policies.Exec]
+//! slow_threshold_ms = 1000
+//! deadline_ms = 10000
+//! ```
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct MethodPolicy {
+    /// Requests that take longer are logged with their client and duration,
+    /// unless they were cancelled at their deadline.
+    pub slow_threshold_ms: Option<u64>,
+    /// Requests that take longer are cancelled with `DEADLINE_EXCEEDED`.
+    ///
+    /// It ends when the response starts, so it does not bound the time spent
+    /// sending a streaming response body.
+    pub deadline_ms: Option<u64>,
+}
+
+impl MethodPolicy {
+    pub fn slow_threshold(&self) -> Option<Duration> {
+        self.slow_threshold_ms.map(Duration::from_millis)
+    }
+
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline_ms.map(Duration::from_millis)
+    }
+}
+
+/// Finds the policy of `service` and `method` in `policies`.
+pub(super) fn method_policy(
+    policies: &HashMap<String, MethodPolicy>,
+    service: &str,
+    method: &str,
+) -> MethodPolicy {
+    lookup(policies, service, method)
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Finds the entry of `service` and `method` in a table keyed by
+/// `Service/Method` or `Method`.
+pub(super) fn lookup<'a, T>(
+    table: &'a HashMap<String, T>,
+    service: &str,
+    method: &str,
+) -> Option<&'a T> {
+    if table.is_empty() {
+        return None;
+    }
+    table
+        .get(&format!("{}/{}", service, method))
+        .or_else(|| table.get(method))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct ControllerConfig {
+        method_policies: HashMap<String, MethodPolicy>,
+    }
+
+    #[test]
+    fn lookup_method_policy() {
+        let mut policies =
+            crate::common::read_toml::<ControllerConfig>("src/example/sample.toml", "controller")
+                .method_policies;
+        let exec = method_policy(&policies, "executor.ExecutorService", "Exec");
+        assert_eq!(exec.slow_threshold(), Some(Duration::from_secs(1)));
+        assert_eq!(exec.deadline(), Some(Duration::from_secs(10)));
+        assert_eq!(
+            method_policy(&policies, "executor.ExecutorService", "Call"),
+            MethodPolicy::default()
+        );
+
+        let call = MethodPolicy {
+            slow_threshold_ms: Some(100),
+            deadline_ms: None,
+        };
+        policies.insert("executor.ExecutorService/Exec".to_string(), call);
+        assert_eq!(
+            method_policy(&policies, "executor.ExecutorService", "Exec"),
+            call
+        );
+        assert_eq!(method_policy(&policies, "other.Service", "Exec"), exec);
+    }
+}
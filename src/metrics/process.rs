@@ -0,0 +1,185 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Process and tokio runtime metrics, enabled by the `process-metrics` feature.
+//!
+//! On linux the `process_*` metrics of the prometheus client are exported:
+//! cpu seconds, resident and virtual memory, open and max fds, threads and
+//! start time. The runtime the exporter runs on is exported as:
+//!
+//! - `tokio_workers`: number of worker threads
+//! - `tokio_alive_tasks`: number of tasks not yet completed
+//! - `tokio_global_queue_depth`: number of tasks in the global queue
+//! - `tokio_worker_busy_seconds_total{worker}`: time each worker spent busy
+//! - `tokio_worker_park_total{worker}`: times each worker parked
+//! - `tokio_worker_local_queue_depth{worker}`: only when built with
+//!   `--cfg tokio_unstable`
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+#[cfg(tokio_unstable)]
+use prometheus::IntGaugeVec;
+use prometheus::{CounterVec, IntCounterVec, IntGauge, Opts, Registry};
+use std::sync::Mutex;
+use tokio::runtime::Handle;
+
+/// Registers the process metrics and the metrics of the current tokio runtime.
+///
+/// Must be called from within a tokio runtime. The process metrics are
+/// already part of the default registry, so registering them again is not an
+/// error.
+pub fn register_process_metrics(registry: &Registry) -> prometheus::Result<()> {
+    #[cfg(target_os = "linux")]
+    match registry.register(Box::new(
+        prometheus::process_collector::ProcessCollector::for_self(),
+    )) {
+        Ok(()) | Err(prometheus::Error::AlreadyReg) => {}
+        Err(e) => return Err(e),
+    }
+
+    let handle = Handle::try_current().map_err(|e| prometheus::Error::Msg(e.to_string()))?;
+    registry.register(Box::new(RuntimeCollector::new(handle)?))
+}
+
+/// Reads the metrics of a tokio runtime on every scrape.
+struct RuntimeCollector {
+    handle: Handle,
+    workers: IntGauge,
+    alive_tasks: IntGauge,
+    global_queue_depth: IntGauge,
+    worker_busy_seconds: CounterVec,
+    worker_parks: IntCounterVec,
+    #[cfg(tokio_unstable)]
+    worker_local_queue_depth: IntGaugeVec,
+    descs: Vec<Desc>,
+    // counters are reset and refilled, so concurrent scrapes must not interleave
+    collecting: Mutex<()>,
+}
+
+impl RuntimeCollector {
+    fn new(handle: Handle) -> prometheus::Result<Self> {
+        let workers = IntGauge::new("tokio_workers", "number of tokio worker threads")?;
+        let alive_tasks = IntGauge::new("tokio_alive_tasks", "number of alive tokio tasks")?;
+        let global_queue_depth = IntGauge::new(
+            "tokio_global_queue_depth",
+            "number of tasks in the tokio global queue",
+        )?;
+        let worker_busy_seconds = CounterVec::new(
+            Opts::new(
+                "tokio_worker_busy_seconds_total",
+                "time each tokio worker spent busy",
+            ),
+            &["worker"],
+        )?;
+        let worker_parks = IntCounterVec::new(
+            Opts::new("tokio_worker_park_total", "times each tokio worker parked"),
+            &["worker"],
+        )?;
+        #[cfg(tokio_unstable)]
+        let worker_local_queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "tokio_worker_local_queue_depth",
+                "number of tasks in the local queue of each tokio worker",
+            ),
+            &["worker"],
+        )?;
+
+        let mut descs = Vec::new();
+        descs.extend(workers.desc().into_iter().cloned());
+        descs.extend(alive_tasks.desc().into_iter().cloned());
+        descs.extend(global_queue_depth.desc().into_iter().cloned());
+        descs.extend(worker_busy_seconds.desc().into_iter().cloned());
+        descs.extend(worker_parks.desc().into_iter().cloned());
+        #[cfg(tokio_unstable)]
+        descs.extend(worker_local_queue_depth.desc().into_iter().cloned());
+
+        Ok(RuntimeCollector {
+            handle,
+            workers,
+            alive_tasks,
+            global_queue_depth,
+            worker_busy_seconds,
+            worker_parks,
+            #[cfg(tokio_unstable)]
+            worker_local_queue_depth,
+            descs,
+            collecting: Mutex::new(()),
+        })
+    }
+}
+
+impl Collector for RuntimeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let _collecting = self.collecting.lock().unwrap();
+        let metrics = self.handle.metrics();
+
+        self.workers.set(metrics.num_workers() as i64);
+        self.alive_tasks.set(metrics.num_alive_tasks() as i64);
+        self.global_queue_depth
+            .set(metrics.global_queue_depth() as i64);
+        for worker in 0..metrics.num_workers() {
+            let label = worker.to_string();
+
+            let busy = self.worker_busy_seconds.with_label_values(&[&label]);
+            busy.reset();
+            busy.inc_by(metrics.worker_total_busy_duration(worker).as_secs_f64());
+
+            let parks = self.worker_parks.with_label_values(&[&label]);
+            parks.reset();
+            parks.inc_by(metrics.worker_park_count(worker));
+
+            #[cfg(tokio_unstable)]
+            self.worker_local_queue_depth
+                .with_label_values(&[&label])
+                .set(metrics.worker_local_queue_depth(worker) as i64);
+        }
+
+        let mut families = Vec::new();
+        families.extend(self.workers.collect());
+        families.extend(self.alive_tasks.collect());
+        families.extend(self.global_queue_depth.collect());
+        families.extend(self.worker_busy_seconds.collect());
+        families.extend(self.worker_parks.collect());
+        #[cfg(tokio_unstable)]
+        families.extend(self.worker_local_queue_depth.collect());
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn collect_runtime_metrics() {
+        let registry = Registry::new();
+        register_process_metrics(&registry).unwrap();
+
+        let families = registry.gather();
+        let workers = families
+            .iter()
+            .find(|mf| mf.get_name() == "tokio_workers")
+            .unwrap();
+        assert_eq!(workers.get_metric()[0].get_gauge().get_value(), 2.0);
+        let parks = families
+            .iter()
+            .find(|mf| mf.get_name() == "tokio_worker_park_total")
+            .unwrap();
+        assert_eq!(parks.get_metric().len(), 2);
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CPU profiles on demand, enabled by the `pprof` feature.
+//!
+//! `GET /debug/pprof/profile?seconds=30` samples the stacks of every thread
+//! of the process for that long, then returns:
+//!
+//! - by default, a gzip compressed pprof protobuf, for `go tool pprof`
+//! - with `format=flamegraph`, an svg flamegraph
+//!
+//! `frequency` sets the samples per second, 99 by default. Only one profile
+//! runs at a time. Only CPU is sampled: there is no heap profile endpoint.
+
+use super::encoding::gzip;
+use hyper::{header::CONTENT_TYPE, Body, Request, Response, StatusCode};
+use log::info;
+use pprof::protos::Message;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+const DEFAULT_FREQUENCY: i32 = 99;
+
+pub(super) async fn serve_profile(req: &Request<Body>) -> Response<Body> {
+    let query: HashMap<&str, &str> = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .collect();
+    let seconds = match query.get("seconds").map(|s| s.parse::<u64>()) {
+        None => DEFAULT_SECONDS,
+        Some(Ok(seconds)) if (1..=MAX_SECONDS).contains(&seconds) => seconds,
+        _ => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("seconds must be between 1 and {}", MAX_SECONDS),
+            )
+        }
+    };
+    let frequency = match query.get("frequency").map(|f| f.parse::<i32>()) {
+        None => DEFAULT_FREQUENCY,
+        Some(Ok(frequency)) if (1..=1000).contains(&frequency) => frequency,
+        _ => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "frequency must be between 1 and 1000".to_string(),
+            )
+        }
+    };
+    let flamegraph = match query.get("format").copied() {
+        None | Some("pprof") => false,
+        Some("flamegraph") => true,
+        Some(_) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "format must be pprof or flamegraph".to_string(),
+            )
+        }
+    };
+
+    // the profiler is global, so building a second guard fails
+    let guard = match pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+    {
+        Ok(guard) => guard,
+        Err(e) => {
+            return error_response(
+                StatusCode::CONFLICT,
+                format!("start profiler failed: {}", e),
+            )
+        }
+    };
+    info!("profiling cpu for {}s at {}Hz", seconds, frequency);
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+
+    let report = match guard.report().build() {
+        Ok(report) => report,
+        Err(e) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("build profile failed: {}", e),
+            )
+        }
+    };
+    drop(guard);
+
+    let body = if flamegraph {
+        let mut svg = Vec::new();
+        report
+            .flamegraph(&mut svg)
+            .map(|()| ("image/svg+xml", svg))
+            .map_err(|e| e.to_string())
+    } else {
+        report
+            .pprof()
+            .map_err(|e| e.to_string())
+            .and_then(|profile| gzip(&profile.encode_to_vec()).map_err(|e| e.to_string()))
+            .map(|profile| ("application/octet-stream", profile))
+    };
+    match body {
+        Ok((content_type, body)) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("encode profile failed: {}", e),
+        ),
+    }
+}
+
+fn error_response(status: StatusCode, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(message + "\n"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn profile_for_one_second() {
+        let profile = |uri: &str| {
+            let req = Request::get(uri).body(Body::empty()).unwrap();
+            async move { serve_profile(&req).await }
+        };
+
+        let response = profile("/debug/pprof/profile?seconds=0").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let running = tokio::spawn(profile("/debug/pprof/profile?seconds=1"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let response = profile("/debug/pprof/profile?seconds=1&format=flamegraph").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = running.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        // gzip magic
+        assert_eq!(&body[..2], &[0x1f, 0x8b]);
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pushes metrics to a Pushgateway, for nodes that prometheus cannot scrape.
+//!
+//! ```toml
+//! [controller.metrics_push]
+//! url = "http://pushgateway:9091"
+//! job = "controller"
+//! instance = "node0"
+//! interval_secs = 15
+//! ```
+
+use super::BasicAuthConfig;
+use futures::Future;
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, Request,
+};
+use log::{info, warn};
+use prometheus::{Encoder, Registry, TextEncoder};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Delay before the first retry of a failed push, doubled for each next one.
+const PUSH_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PushConfig {
+    /// Base url of the Pushgateway, only `http` is supported.
+    pub url: String,
+    /// `job` grouping label.
+    pub job: String,
+    /// `instance` grouping label.
+    pub instance: String,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Retries of a failed push before waiting for the next interval.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuthConfig>,
+}
+
+fn default_interval_secs() -> u64 {
+    15
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+impl PushConfig {
+    /// Also the timeout of a push, so that a stalled push gateway does not
+    /// hold up the next one.
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
+
+    /// `{url}/metrics/job/{job}/instance/{instance}`, see
+    /// <https://github.com/prometheus/pushgateway#url>.
+    fn push_url(&self) -> String {
+        format!(
+            "{}/metrics/{}/{}",
+            self.url.trim_end_matches('/'),
+            grouping_label("job", &self.job),
+            grouping_label("instance", &self.instance),
+        )
+    }
+}
+
+/// Encodes a grouping label as a url path, in base64 unless the value is url safe.
+fn grouping_label(name: &str, value: &str) -> String {
+    let url_safe = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if url_safe {
+        format!("{}/{}", name, value)
+    } else {
+        format!(
+            "{}@base64/{}",
+            name,
+            base64::encode_config(value, base64::URL_SAFE_NO_PAD)
+        )
+    }
+}
+
+/// Why a push failed, and whether it is worth retrying.
+enum PushError {
+    Retryable(String),
+    Fatal(String),
+}
+
+async fn push_once(
+    client: &Client<hyper::client::HttpConnector>,
+    config: &PushConfig,
+    registry: &Registry,
+) -> Result<(), PushError> {
+    let mut buffer = vec![];
+    let encoder = TextEncoder::new();
+    encoder
+        .encode(&registry.gather(), &mut buffer)
+        .map_err(|e| PushError::Fatal(format!("encode metrics failed: {}", e)))?;
+
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(config.push_url())
+        .header(CONTENT_TYPE, encoder.format_type());
+    if let Some(basic) = &config.basic_auth {
+        let credentials = format!("{}:{}", basic.username, basic.password);
+        req = req.header(
+            AUTHORIZATION,
+            format!("Basic {}", base64::encode(credentials)),
+        );
+    }
+    let req = req
+        .body(Body::from(buffer))
+        .map_err(|e| PushError::Fatal(format!("build push request failed: {}", e)))?;
+
+    let response = client
+        .request(req)
+        .await
+        .map_err(|e| PushError::Retryable(e.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else if status.is_server_error() {
+        Err(PushError::Retryable(format!(
+            "pushgateway returned {}",
+            status
+        )))
+    } else {
+        Err(PushError::Fatal(format!("pushgateway returned {}", status)))
+    }
+}
+
+/// [`push_once`], failing if it does not complete within the interval.
+async fn push_in_time(
+    client: &Client<hyper::client::HttpConnector>,
+    config: &PushConfig,
+    registry: &Registry,
+) -> Result<(), PushError> {
+    let timeout = config.interval();
+    tokio::time::timeout(timeout, push_once(client, config, registry))
+        .await
+        .unwrap_or_else(|_| {
+            Err(PushError::Retryable(format!(
+                "timed out after {}s",
+                timeout.as_secs()
+            )))
+        })
+}
+
+async fn push_with_retry(
+    client: &Client<hyper::client::HttpConnector>,
+    config: &PushConfig,
+    registry: &Registry,
+) {
+    let mut backoff = PUSH_RETRY_BACKOFF;
+    for attempt in 0..=config.max_retries {
+        match push_in_time(client, config, registry).await {
+            Ok(()) => return,
+            Err(PushError::Fatal(e)) => {
+                warn!("push metrics to {} failed: {}", config.url, e);
+                return;
+            }
+            Err(PushError::Retryable(e)) => {
+                warn!(
+                    "push metrics to {} failed(attempt {}): {}",
+                    config.url,
+                    attempt + 1,
+                    e
+                );
+                if attempt < config.max_retries {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+}
+
+/// Pushes the metrics gathered from `registry` every `interval_secs` until
+/// `shutdown` completes, then pushes them a last time, without retries.
+///
+/// Shutdown cancels a push in progress, and each push times out after the
+/// interval.
+pub async fn run_metrics_pusher(
+    config: PushConfig,
+    registry: Registry,
+    shutdown: impl Future<Output = ()>,
+) {
+    let client = Client::new();
+    let mut interval = tokio::time::interval(config.interval());
+    info!("pushing metrics to {}", config.push_url());
+
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = async {
+                interval.tick().await;
+                push_with_retry(&client, &config, &registry).await
+            } => {}
+            _ = &mut shutdown => break,
+        }
+    }
+    if let Err(PushError::Retryable(e) | PushError::Fatal(e)) =
+        push_in_time(&client, &config, &registry).await
+    {
+        warn!("push metrics to {} failed: {}", config.url, e);
+    }
+    info!("metrics pusher to {} stopped", config.url);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn encode_grouping_labels() {
+        let mut config = PushConfig {
+            url: "http://127.0.0.1:9091/".to_string(),
+            job: "controller".to_string(),
+            instance: "node0".to_string(),
+            interval_secs: 15,
+            max_retries: 3,
+            basic_auth: None,
+        };
+        assert_eq!(
+            config.push_url(),
+            "http://127.0.0.1:9091/metrics/job/controller/instance/node0"
+        );
+        config.instance = "10.0.0.1:50004".to_string();
+        assert_eq!(
+            config.push_url(),
+            "http://127.0.0.1:9091/metrics/job/controller/instance@base64/MTAuMC4wLjE6NTAwMDQ"
+        );
+    }
+
+    #[tokio::test]
+    async fn push_to_stub_gateway() {
+        // fails the first push to check that it is retried
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (pushed_tx, pushed_rx) = futures::channel::oneshot::channel();
+        let pushed_tx = Arc::new(std::sync::Mutex::new(Some(pushed_tx)));
+        let make_svc = {
+            let requests = requests.clone();
+            make_service_fn(move |_conn| {
+                let requests = requests.clone();
+                let pushed_tx = pushed_tx.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let requests = requests.clone();
+                        let pushed_tx = pushed_tx.clone();
+                        async move {
+                            let path = req.uri().path().to_string();
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            let status = if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                                StatusCode::SERVICE_UNAVAILABLE
+                            } else {
+                                if let Some(tx) = pushed_tx.lock().unwrap().take() {
+                                    tx.send((path, body)).unwrap();
+                                }
+                                StatusCode::OK
+                            };
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .status(status)
+                                    .body(Body::empty())
+                                    .unwrap(),
+                            )
+                        }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let registry = Registry::new();
+        let counter = prometheus::IntCounter::new("pushed_total", "test counter").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+        let config = PushConfig {
+            url: format!("http://{}", addr),
+            job: "controller".to_string(),
+            instance: "node0".to_string(),
+            interval_secs: 60,
+            max_retries: 3,
+            basic_auth: None,
+        };
+
+        run_metrics_pusher(config, registry, async {
+            let (path, body) = pushed_rx.await.unwrap();
+            assert_eq!(path, "/metrics/job/controller/instance/node0");
+            assert!(String::from_utf8(body.to_vec())
+                .unwrap()
+                .contains("pushed_total 1"));
+        })
+        .await;
+        // the failed push, its retry and the final push
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn stop_while_gateway_stalls() {
+        // accepts connections and never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut conns = vec![];
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+
+        let config = PushConfig {
+            url: format!("http://{}", addr),
+            job: "controller".to_string(),
+            instance: "node0".to_string(),
+            interval_secs: 1,
+            max_retries: 3,
+            basic_auth: None,
+        };
+        let pusher = run_metrics_pusher(config, Registry::new(), async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        });
+        // the first push is cancelled and the last one times out after 1s
+        assert!(tokio::time::timeout(Duration::from_secs(3), pusher)
+            .await
+            .is_ok());
+    }
+}
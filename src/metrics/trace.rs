@@ -0,0 +1,515 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Distributed tracing with [W3C trace context](https://www.w3.org/TR/trace-context/)
+//! propagation.
+//!
+//! [`TraceLayer`] goes next to [`MiddlewareLayer`](super::MiddlewareLayer) on
+//! servers. It continues the trace of the `traceparent` request header, or
+//! starts a new one, and handles the request in a `grpc.server` span of
+//! [`tracing`] with these fields:
+//!
+//! - `trace_id`, `span_id` and `parent_span_id`: hex encoded ids
+//! - `sampled`: the sampled flag of the trace, unsampled spans are not exported
+//! - `rpc.service` and `rpc.method`: the gRPC service and method
+//! - `rpc.grpc.status_code`: status code of trailers-only responses, which
+//!   tonic uses for errors
+//!
+//! The [`TraceContext`] of the request is current while it is handled, and
+//! [`TraceInterceptor`] adds it to the outgoing calls of clients built with
+//! it, so that the next service continues the same trace:
+//!
+//! ```ignore
+//! let client = CryptoServiceClient::with_interceptor(channel, TraceInterceptor);
+//! ```
+//!
+//! The helpers of [`crypto`](crate::crypto) and [`storage`](crate::storage)
+//! take any client of the client traits of `cita_cloud_proto`, and
+//! [`TracedClient`] is one that adds the context too.
+//!
+//! Work that does not start from a request, e.g. proposing a block, can start
+//! a trace with `TraceContext::new_root().scope(...)`.
+//!
+//! The spans only go where the `tracing` subscriber sends them; with the
+//! `otlp` feature they can be exported to an OpenTelemetry collector, see
+//! [`otlp`](super::otlp).
+
+use super::{grpc_status_code, parse_grpc_path, UNKNOWN_LABEL};
+use crate::common::{decode_hex, encode_hex};
+use cita_cloud_proto::client::{CryptoClientTrait, StorageClientTrait};
+use cita_cloud_proto::common::{HashResponse, StatusCode};
+use cita_cloud_proto::crypto::crypto_service_client::CryptoServiceClient;
+use cita_cloud_proto::crypto::{
+    HashDataRequest, RecoverSignatureRequest, RecoverSignatureResponse, SignMessageRequest,
+    SignMessageResponse,
+};
+use cita_cloud_proto::storage::storage_service_client::StorageServiceClient;
+use cita_cloud_proto::storage::{Content, ExtKey, Value};
+use futures::Future;
+use hyper::{Request, Response};
+use log::warn;
+use rand::Rng;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tonic::body::BoxBody;
+use tonic::client::GrpcService;
+use tonic::codegen::{Body, Bytes, StdError};
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::Status;
+use tower::{Layer, Service};
+use tracing::Instrument;
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// Position of a span in a distributed trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Starts a new sampled trace.
+    pub fn new_root() -> Self {
+        TraceContext {
+            trace_id: random_id(),
+            span_id: random_id(),
+            sampled: true,
+        }
+    }
+
+    /// A new span of the same trace.
+    pub fn child(&self) -> Self {
+        TraceContext {
+            span_id: random_id(),
+            ..*self
+        }
+    }
+
+    /// Parses a `traceparent` header, `{version}-{trace-id}-{parent-id}-{flags}`.
+    ///
+    /// Headers of later versions are parsed as version `00`, as the spec asks.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next().filter(|v| v.len() == 2)?;
+        let trace_id = decode_id::<16>(parts.next()?)?;
+        let span_id = decode_id::<8>(parts.next()?)?;
+        let flags = decode_id::<1>(parts.next()?)?[0];
+        let version = decode_id::<1>(version)?[0];
+        if version == 0xff
+            || (version == 0 && parts.next().is_some())
+            || trace_id == [0; 16]
+            || span_id == [0; 8]
+        {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    /// Formats the context as a version `00` `traceparent` header.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id_hex(),
+            self.span_id_hex(),
+            self.sampled as u8
+        )
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        encode_hex(&self.trace_id)
+    }
+
+    pub fn span_id_hex(&self) -> String {
+        encode_hex(&self.span_id)
+    }
+
+    /// The context of the request being handled by the current task, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|context| *context).ok()
+    }
+
+    /// Runs `f` with this context as the current one.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CURRENT.scope(self, f).await
+    }
+
+    /// Adds the `traceparent` of this context to outgoing request metadata.
+    pub fn inject(&self, metadata: &mut MetadataMap) {
+        if let Ok(value) = MetadataValue::try_from(self.traceparent()) {
+            metadata.insert(TRACEPARENT_HEADER, value);
+        }
+    }
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    // all zero ids are invalid
+    let mut id = [0; N];
+    while id == [0; N] {
+        rand::thread_rng().fill(&mut id[..]);
+    }
+    id
+}
+
+/// Decodes lowercase hex of exactly `N` bytes.
+fn decode_id<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.bytes().any(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    decode_hex(s).ok()?.try_into().ok()
+}
+
+/// Adds the current [`TraceContext`] to outgoing requests.
+///
+/// Requests made outside of a traced request are sent without one, and start
+/// a new trace in the service they call.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TraceInterceptor;
+
+impl Interceptor for TraceInterceptor {
+    fn call(&mut self, mut request: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
+        if let Some(context) = TraceContext::current() {
+            context.inject(request.metadata_mut());
+        }
+        Ok(request)
+    }
+}
+
+/// A crypto or storage service client whose calls carry the current
+/// [`TraceContext`], for the helpers that take a client of the client traits:
+///
+/// ```ignore
+/// let client = TracedClient::new(CryptoServiceClient::new(channel));
+/// let hash = crypto::hash_data(client, data).await?;
+/// ```
+///
+/// The requests of the `RetryClient` of `cita_cloud_proto` cannot carry the
+/// context, so it retries failed calls itself instead, by default up to
+/// [`DEFAULT_CALL_RETRIES`] times [`DEFAULT_RETRY_INTERVAL`] apart; see
+/// [`TracedClient::with_retries`].
+#[derive(Debug, Clone)]
+pub struct TracedClient<T> {
+    inner: T,
+    retries: usize,
+    interval: Duration,
+}
+
+/// Retries of a failed call of a [`TracedClient`] by default.
+pub const DEFAULT_CALL_RETRIES: usize = 3;
+/// Time between the retries of a [`TracedClient`] by default.
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+impl<T: Clone> TracedClient<T> {
+    pub fn new(inner: T) -> Self {
+        TracedClient {
+            inner,
+            retries: DEFAULT_CALL_RETRIES,
+            interval: DEFAULT_RETRY_INTERVAL,
+        }
+    }
+
+    /// Retries a failed call up to `retries` times, `interval` apart.
+    pub fn with_retries(mut self, retries: usize, interval: Duration) -> Self {
+        self.retries = retries;
+        self.interval = interval;
+        self
+    }
+
+    /// Runs `call` with a traced request of `msg` until it succeeds or runs
+    /// out of retries.
+    async fn call<M, R, F, Fut>(&self, method: &str, msg: M, mut call: F) -> Result<R, Status>
+    where
+        M: Clone,
+        F: FnMut(T, tonic::Request<M>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<R>, Status>>,
+    {
+        let mut retries = 0;
+        loop {
+            match call(self.inner.clone(), traced_request(msg.clone())).await {
+                Ok(response) => return Ok(response.into_inner()),
+                Err(status) if retries < self.retries => {
+                    retries += 1;
+                    warn!(
+                        "{} failed: {}, retry {} of {}",
+                        method,
+                        status.message(),
+                        retries,
+                        self.retries
+                    );
+                    tokio::time::sleep(self.interval).await;
+                }
+                Err(status) => return Err(status),
+            }
+        }
+    }
+}
+
+/// A request with the current context, as [`TraceInterceptor`] adds it.
+fn traced_request<M>(msg: M) -> tonic::Request<M> {
+    let mut request = tonic::Request::new(msg);
+    if let Some(context) = TraceContext::current() {
+        context.inject(request.metadata_mut());
+    }
+    request
+}
+
+#[tonic::async_trait]
+impl<T> CryptoClientTrait for TracedClient<CryptoServiceClient<T>>
+where
+    T: GrpcService<BoxBody> + Clone + Send + Sync + 'static,
+    T::Error: Into<StdError>,
+    T::Future: Send,
+    T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+    <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+{
+    async fn hash_data(&self, r: HashDataRequest) -> Result<HashResponse, Status> {
+        self.call("hash_data", r, |mut client, req| async move {
+            client.hash_data(req).await
+        })
+        .await
+    }
+
+    async fn sign_message(&self, r: SignMessageRequest) -> Result<SignMessageResponse, Status> {
+        self.call("sign_message", r, |mut client, req| async move {
+            client.sign_message(req).await
+        })
+        .await
+    }
+
+    async fn recover_signature(
+        &self,
+        r: RecoverSignatureRequest,
+    ) -> Result<RecoverSignatureResponse, Status> {
+        self.call("recover_signature", r, |mut client, req| async move {
+            client.recover_signature(req).await
+        })
+        .await
+    }
+}
+
+#[tonic::async_trait]
+impl<T> StorageClientTrait for TracedClient<StorageServiceClient<T>>
+where
+    T: GrpcService<BoxBody> + Clone + Send + Sync + 'static,
+    T::Error: Into<StdError>,
+    T::Future: Send,
+    T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+    <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+{
+    async fn store(&self, content: Content) -> Result<StatusCode, Status> {
+        self.call("store", content, |mut client, req| async move {
+            client.store(req).await
+        })
+        .await
+    }
+
+    async fn load(&self, key: ExtKey) -> Result<Value, Status> {
+        self.call("load", key, |mut client, req| async move {
+            client.load(req).await
+        })
+        .await
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TraceLayer;
+
+impl<S> Layer<S> for TraceLayer {
+    type Service = TraceService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        TraceService { inner: service }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for TraceService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let parent = req
+            .headers()
+            .get(TRACEPARENT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(TraceContext::parse);
+        let context = match parent {
+            Some(parent) => parent.child(),
+            None => TraceContext::new_root(),
+        };
+        let (service, method) =
+            parse_grpc_path(req.uri().path()).unwrap_or((UNKNOWN_LABEL, UNKNOWN_LABEL));
+        let span = tracing::info_span!(
+            "grpc.server",
+            trace_id = %context.trace_id_hex(),
+            span_id = %context.span_id_hex(),
+            parent_span_id = %parent.map(|p| p.span_id_hex()).unwrap_or_default(),
+            sampled = context.sampled,
+            rpc.service = service,
+            rpc.method = method,
+            rpc.grpc.status_code = tracing::field::Empty,
+        );
+        req.extensions_mut().insert(context);
+
+        let handled = span.clone();
+        Box::pin(
+            context.scope(
+                async move {
+                    let response = inner.call(req).await?;
+                    if let Some(code) = grpc_status_code(response.headers()) {
+                        handled.record("rpc.grpc.status_code", code.as_str());
+                    }
+                    Ok(response)
+                }
+                .instrument(span),
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::HttpBody;
+    use hyper::Body;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn parse_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(header).unwrap();
+        assert!(context.sampled);
+        assert_eq!(context.span_id_hex(), "00f067aa0ba902b7");
+        assert_eq!(context.traceparent(), header);
+
+        // later versions may append fields
+        assert!(
+            TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-ff")
+                .is_some()
+        );
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-ff",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(TraceContext::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn propagate_trace_context() {
+        let service = tower::service_fn(|req: Request<Body>| async move {
+            let context = *req.extensions().get::<TraceContext>().unwrap();
+            assert_eq!(TraceContext::current(), Some(context));
+
+            let mut outgoing = TraceInterceptor.call(tonic::Request::new(())).unwrap();
+            let traceparent = outgoing.metadata_mut().remove(TRACEPARENT_HEADER).unwrap();
+            assert_eq!(traceparent.to_str().unwrap(), context.traceparent());
+            let traced = traced_request(());
+            assert_eq!(
+                traced.metadata().get(TRACEPARENT_HEADER),
+                Some(&traceparent)
+            );
+            Ok::<_, Status>(Response::new(context))
+        });
+        let mut service = TraceLayer.layer(service);
+
+        let parent = TraceContext::new_root();
+        let req = Request::builder()
+            .uri("/crypto.CryptoService/HashData")
+            .header(TRACEPARENT_HEADER, parent.traceparent())
+            .body(Body::empty())
+            .unwrap();
+        let context = service.call(req).await.unwrap().into_body();
+        assert_eq!(context.trace_id, parent.trace_id);
+        assert_ne!(context.span_id, parent.span_id);
+        assert_eq!(TraceContext::current(), None);
+        assert!(traced_request(()).metadata().is_empty());
+    }
+
+    #[tokio::test]
+    async fn retry_traced_calls() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        // only the third call answers, with an empty HashResponse
+        let channel = tower::service_fn(move |req: Request<BoxBody>| {
+            let call = counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                assert!(req.headers().contains_key(TRACEPARENT_HEADER));
+                if call != 2 {
+                    return Ok::<_, Status>(Status::unavailable("down").to_http());
+                }
+                let response = Response::builder()
+                    .header("content-type", "application/grpc")
+                    .header("grpc-status", "0")
+                    .body(
+                        Body::from(vec![0u8; 5])
+                            .map_err(|e| Status::internal(e.to_string()))
+                            .boxed_unsync(),
+                    )
+                    .unwrap();
+                Ok(response)
+            }
+        });
+        let client = TracedClient::new(CryptoServiceClient::new(channel))
+            .with_retries(2, Duration::from_millis(1));
+
+        let hash = TraceContext::new_root()
+            .scope(client.hash_data(HashDataRequest { data: vec![1] }))
+            .await;
+        assert!(hash.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let failed = TraceContext::new_root()
+            .scope(
+                client
+                    .with_retries(1, Duration::from_millis(1))
+                    .hash_data(HashDataRequest { data: vec![1] }),
+            )
+            .await;
+        assert_eq!(failed.unwrap_err().code(), tonic::Code::Unavailable);
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+}
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::metrics::observe_client_call;
 use cita_cloud_proto::client::{InterceptedSvc, NetworkClientTrait};
 use cita_cloud_proto::network::network_service_client::NetworkServiceClient;
 use cita_cloud_proto::network::RegisterInfo;
@@ -23,11 +24,14 @@ pub async fn register_network_msg_handler(
     client: RetryClient<NetworkServiceClient<InterceptedSvc>>,
     register_info: RegisterInfo,
 ) -> StatusCode {
-    match client.register_network_msg_handler(register_info).await {
-        Ok(code) => StatusCode::from(code),
-        Err(status) => {
-            warn!("register_network_msg_handler error: {}", status.to_string());
-            StatusCode::NetworkServerNotReady
+    observe_client_call("network", "register_network_msg_handler", async move {
+        match client.register_network_msg_handler(register_info).await {
+            Ok(code) => StatusCode::from(code),
+            Err(status) => {
+                warn!("register_network_msg_handler error: {}", status.to_string());
+                StatusCode::NetworkServerNotReady
+            }
         }
-    }
+    })
+    .await
 }
@@ -29,3 +29,11 @@ pub async fn handle_signals() {
         }
     }
 }
+
+/// Completes on `SIGTERM` instead of exiting, so that servers can be shut down gracefully.
+pub async fn shutdown_signal() {
+    let mut signals = Signals::new([SIGTERM]).unwrap();
+    if let Some(signal) = signals.next().await {
+        info!("shutdown by signal: {signal}");
+    }
+}
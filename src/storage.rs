@@ -12,39 +12,44 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use cita_cloud_proto::client::{InterceptedSvc, StorageClientTrait};
-use cita_cloud_proto::retry::RetryClient;
-use cita_cloud_proto::storage::storage_service_client::StorageServiceClient;
+use crate::metrics::observe_client_call;
+use cita_cloud_proto::client::StorageClientTrait;
 use cita_cloud_proto::storage::{Content, ExtKey};
 use status_code::StatusCode;
 
-pub async fn store_data(
-    client: RetryClient<StorageServiceClient<InterceptedSvc>>,
+pub async fn store_data<C: StorageClientTrait>(
+    client: C,
     region: u32,
     key: Vec<u8>,
     value: Vec<u8>,
 ) -> StatusCode {
-    let content = Content { region, key, value };
-    match client.store(content.clone()).await {
-        Ok(code) => StatusCode::from(code),
-        Err(e) => {
-            log::warn!("store_data({:?}) failed: {}", content, e.to_string());
-            StatusCode::StorageServerNotReady
+    observe_client_call("storage", "store_data", async move {
+        let content = Content { region, key, value };
+        match client.store(content.clone()).await {
+            Ok(code) => StatusCode::from(code),
+            Err(e) => {
+                log::warn!("store_data({:?}) failed: {}", content, e.to_string());
+                StatusCode::StorageServerNotReady
+            }
         }
-    }
+    })
+    .await
 }
 
-pub async fn load_data(
-    client: RetryClient<StorageServiceClient<InterceptedSvc>>,
+pub async fn load_data<C: StorageClientTrait>(
+    client: C,
     region: u32,
     key: Vec<u8>,
 ) -> Result<Vec<u8>, StatusCode> {
-    let ext_key = ExtKey { region, key };
-    let value = client.load(ext_key.clone()).await.map_err(|e| {
-        log::warn!("load_data({:?}) failed: {}", ext_key, e.to_string());
-        StatusCode::StorageServerNotReady
-    })?;
+    observe_client_call("storage", "load_data", async move {
+        let ext_key = ExtKey { region, key };
+        let value = client.load(ext_key.clone()).await.map_err(|e| {
+            log::warn!("load_data({:?}) failed: {}", ext_key, e.to_string());
+            StatusCode::StorageServerNotReady
+        })?;
 
-    StatusCode::from(value.status.ok_or(StatusCode::NoneStatusCode)?).is_success()?;
-    Ok(value.value)
+        StatusCode::from(value.status.ok_or(StatusCode::NoneStatusCode)?).is_success()?;
+        Ok(value.value)
+    })
+    .await
 }
@@ -0,0 +1,192 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fixed size addresses and hashes.
+//!
+//! They parse from hex with or without `0x`, display with `0x`, and are hex
+//! strings in serde, so config fields can use them directly:
+//!
+//! ```ignore
+//! #[derive(Deserialize)]
+//! struct SysConfig {
+//!     admin: H160,
+//!     validators: Vec<H160>,
+//! }
+//! ```
+
+use crate::clean_0x;
+use crate::common::{decode_hex, encode_hex, ADDR_BYTES_LEN, HASH_BYTES_LEN};
+use cita_cloud_proto::common::{Address, Hash};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use status_code::StatusCode;
+use std::fmt;
+use std::str::FromStr;
+
+macro_rules! fixed_bytes {
+    ($name:ident, $len:expr, $len_error:expr) => {
+        #[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(pub [u8; $len]);
+
+        impl $name {
+            pub const LEN: usize = $len;
+
+            pub fn as_bytes(&self) -> &[u8] {
+                &self.0
+            }
+
+            pub fn to_vec(&self) -> Vec<u8> {
+                self.0.to_vec()
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        impl From<[u8; $len]> for $name {
+            fn from(bytes: [u8; $len]) -> Self {
+                $name(bytes)
+            }
+        }
+
+        impl TryFrom<&[u8]> for $name {
+            type Error = StatusCode;
+
+            fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+                bytes.try_into().map($name).map_err(|_| $len_error)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = StatusCode;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let s = clean_0x(s);
+                if s.len() != $len * 2 {
+                    return Err($len_error);
+                }
+                decode_hex(s)?.as_slice().try_into()
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "0x{}", encode_hex(&self.0))
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Display::fmt(self, f)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(|e| {
+                    de::Error::custom(format!("invalid {} `{}`: {}", stringify!($name), s, e))
+                })
+            }
+        }
+    };
+}
+
+fixed_bytes!(H160, ADDR_BYTES_LEN, StatusCode::ProvideAddressError);
+fixed_bytes!(H256, HASH_BYTES_LEN, StatusCode::HashLenError);
+
+impl From<H160> for Address {
+    fn from(address: H160) -> Self {
+        Address {
+            address: address.to_vec(),
+        }
+    }
+}
+
+impl TryFrom<&Address> for H160 {
+    type Error = StatusCode;
+
+    fn try_from(address: &Address) -> Result<Self, Self::Error> {
+        address.address.as_slice().try_into()
+    }
+}
+
+impl From<H256> for Hash {
+    fn from(hash: H256) -> Self {
+        Hash {
+            hash: hash.to_vec(),
+        }
+    }
+}
+
+impl TryFrom<&Hash> for H256 {
+    type Error = StatusCode;
+
+    fn try_from(hash: &Hash) -> Result<Self, Self::Error> {
+        hash.hash.as_slice().try_into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, serde_derive::Deserialize)]
+    struct SysConfig {
+        chain_id: H256,
+        admin: H160,
+        validators: Vec<H160>,
+    }
+
+    #[test]
+    fn parse_sys_config() {
+        let config: SysConfig =
+            crate::common::read_toml("src/example/sample.toml", "init-sys-config");
+        assert_eq!(
+            config.admin.to_string(),
+            "0x00e68f2cc4ac05b9ef47994a1269e8932c5cf5a2"
+        );
+        assert_eq!(config.validators.len(), 4);
+        assert_eq!(config.chain_id.as_bytes()[0], 0x26);
+
+        let admin: H160 = "00e68f2cc4ac05b9ef47994a1269e8932c5cf5a2".parse().unwrap();
+        assert_eq!(admin, config.admin);
+        assert_eq!(H160::try_from(&Address::from(admin)), Ok(admin));
+        assert_eq!(
+            "0x00e68f".parse::<H160>(),
+            Err(StatusCode::ProvideAddressError)
+        );
+        assert_eq!(
+            "zz".repeat(32).parse::<H256>(),
+            Err(StatusCode::DecodeError)
+        );
+        assert_eq!(
+            format!("+f{}", "0".repeat(38)).parse::<H160>(),
+            Err(StatusCode::DecodeError)
+        );
+        assert_eq!(
+            serde_json::to_string(&config.chain_id).unwrap(),
+            "\"0x26b0b83e7281be3b117658b6f2636d0368cad3d74f22243428f5401a4b70897e\""
+        );
+    }
+}